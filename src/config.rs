@@ -0,0 +1,110 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// Name of the directory we keep our files in, inside the user's config dir
+const APP_DIR: &str = "snakes";
+/// and the name of the config file itself
+const CONFIG_FILE: &str = "snakes.conf";
+
+/// The user's settings. The config file is a plain list of `key = value`
+/// lines, where empty lines and lines starting with `#` are ignored, e.g.
+///
+/// ```text
+/// # Play on WASD, but turn left with Q
+/// keys1.layout = wasd
+/// keys1.left = Q
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    values: BTreeMap<String, String>,
+}
+
+/// The directory we keep our files in. This is `snakes` inside the user's
+/// config directory, or the current directory if we can't find one.
+pub fn app_dir() -> PathBuf {
+    match dirs::config_dir() {
+        Some(dir) => dir.join(APP_DIR),
+        None      => PathBuf::from("."),
+    }
+}
+
+impl Config {
+    /// Load the config file, falling back to the defaults if it doesn't
+    /// exist or can't be read.
+    pub fn load() -> Self {
+        match fs::read_to_string(app_dir().join(CONFIG_FILE)) {
+            Ok(contents) => Self::parse(&contents),
+            Err(_)       => Self::default(),
+        }
+    }
+
+    pub fn parse(contents: &str) -> Self {
+        let mut values = BTreeMap::new();
+
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if let Some((key, value)) = line.split_once('=') {
+                values.insert(key.trim().to_string(), value.trim().to_string());
+            }
+        }
+
+        Config { values }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(|value| value.as_str())
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) {
+        self.values.insert(key.to_string(), value.to_string());
+    }
+
+    /// Write the config back to its file. Lines with a key we know are
+    /// changed in place, and everything else, comments included, stays as
+    /// it was. Keys the file didn't have yet go at the end.
+    pub fn save(&self) -> io::Result<()> {
+        let dir = app_dir();
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(CONFIG_FILE);
+        let old = fs::read_to_string(&path).unwrap_or_default();
+
+        let mut written = BTreeSet::new();
+        let mut lines: Vec<String> = old
+            .lines()
+            .map(|line| {
+                let key = match line.split_once('=') {
+                    Some((key, _)) if !line.trim_start().starts_with('#') => key.trim(),
+                    _ => return line.to_string(),
+                };
+                match self.values.get(key) {
+                    Some(value) => {
+                        written.insert(key);
+                        format!("{} = {}", key, value)
+                    }
+                    None => line.to_string(),
+                }
+            })
+            .collect();
+        for (key, value) in &self.values {
+            if !written.contains(key.as_str()) {
+                lines.push(format!("{} = {}", key, value));
+            }
+        }
+
+        fs::write(path, lines.join("\n") + "\n")
+    }
+
+    /// Every key that starts with `prefix`, without it, along with its value
+    pub fn with_prefix<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = (&'a str, &'a str)> + 'a {
+        self.values
+            .iter()
+            .filter_map(move |(key, value)| Some((key.strip_prefix(prefix)?, value.as_str())))
+    }
+}
+
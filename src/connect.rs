@@ -1,55 +1,424 @@
 use std::thread;
-use std::net::{TcpListener, TcpStream, Shutdown};
-use std::io::{Read, Write};
-use super::{Mode::{Server, Client}, ConnectionStatus, InitState};
-use super::game;
+use std::net::{SocketAddr, TcpListener, TcpStream, Shutdown};
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use super::{Mode::{self, Server, Client, Local, Single}, ConnectionStatus, Difficulty};
+use super::config::Config;
+use super::error::Error;
+use super::game::{
+    self, secure, Link, Profile, RemoteBot, SavedGame, Spectators, Transport, UdpLink, WebSocketLink, WATCH_PATH,
+};
+use super::netsim;
 
-use byteorder::{BigEndian, WriteBytesExt};
+use druid::{ExtEventSink, Selector, Target};
+use log::{error, info, warn};
+
+/// Tells the launcher how an attempt is going, along with which attempt it is
+pub const STATUS: Selector<(u32, ConnectionStatus)> = Selector::new("snakes.connection-status");
+
+/// How often the server looks up from waiting to see if it was cancelled
+const CANCEL_POLL: Duration = Duration::from_millis(100);
+
+/// How long the server waits for someone who connected to say something
+const GREET_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long sending the board to a spectator can take before we give up on
+/// them
+const SPECTATOR_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How many players the server waits for before a match can start
+pub const PLAYERS_NEEDED: u32 = 1;
 
 pub fn make_ip(ip1: String, ip2: String, ip3: String, ip4: String) -> String {
     [ip1, ip2, ip3, ip4].join(".")
 }
 
-pub fn server_main(ip_address: String, port: String, state: &mut InitState) {
+/// What an attempt to start a game has to tell the launcher, which is running
+/// on another thread. Every attempt has a number, so the launcher can tell
+/// news from an attempt it has already given up on.
+pub struct Progress {
+    sink: ExtEventSink,
+    attempt: u32,
+    cancelled: Arc<AtomicBool>,
+    fill_seat: Arc<AtomicBool>,
+}
+
+impl Progress {
+    pub fn new(sink: ExtEventSink, attempt: u32, cancelled: Arc<AtomicBool>, fill_seat: Arc<AtomicBool>) -> Self {
+        Progress { sink, attempt, cancelled, fill_seat }
+    }
+
+    pub fn report(&self, status: ConnectionStatus) {
+        // The launcher is gone if this fails, and then nobody is looking
+        let _ = self.sink.submit_command(STATUS, (self.attempt, status), Target::Auto);
+    }
+
+    /// Whether the player pressed Cancel and we should stop waiting
+    pub fn cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Whether the server's player would rather play the bot than wait for
+    /// somebody to join
+    pub fn filling_seat(&self) -> bool {
+        self.fill_seat.load(Ordering::Relaxed)
+    }
+
+    /// Tell the launcher how the attempt ended
+    pub fn finish(&self, result: Result<(), Error>) {
+        match result {
+            // The launcher isn't listening to this attempt anymore
+            Err(_) if self.cancelled() => info!("Gave up on the match, as asked"),
+            Ok(()) => self.report(ConnectionStatus::MatchOver),
+            Err(e) => {
+                error!("{}", e);
+                self.report(ConnectionStatus::Failed(e.to_string()));
+            }
+        }
+    }
+}
+
+/// Put the simulated network in front of a TCP connection if the config
+/// asks for one.
+fn simulated(stream: TcpStream) -> io::Result<TcpStream> {
+    match netsim::Settings::from_config(&Config::load()) {
+        Some(settings) => netsim::wrap(stream, settings),
+        None => Ok(stream),
+    }
+}
+
+/// Put the simulated network in front of a UDP link if the config asks for
+/// one.
+fn simulated_udp(link: UdpLink) -> io::Result<UdpLink> {
+    match netsim::Settings::from_config(&Config::load()) {
+        Some(settings) => link.simulated(settings),
+        None => Ok(link),
+    }
+}
+
+/// Hand a TCP connection over to the game
+fn tcp_link(stream: TcpStream) -> io::Result<Box<dyn Link>> {
+    Ok(Box::new(simulated(stream)?))
+}
+
+/// If the match has a passphrase, check that the other side knows it too,
+/// and encrypt everything from then on.
+fn secured(link: Box<dyn Link>, mode: Mode, passphrase: &str) -> io::Result<Box<dyn Link>> {
+    if passphrase.is_empty() {
+        return Ok(link);
+    }
+    match mode {
+        Server => Ok(Box::new(secure::server_handshake(link, passphrase)?)),
+        _      => Ok(Box::new(secure::client_handshake(link, passphrase)?)),
+    }
+}
+
+pub fn server_main(
+    ip_address: String,
+    port: String,
+    profile: Profile,
+    bot: Option<Difficulty>,
+    passphrase: String,
+    progress: &Progress,
+) -> Result<(), Error> {
     let ip = [ip_address, port].join(":");
-    let listener = TcpListener::bind(&ip).unwrap();
-    // Accept connections and process them, spawing a new thread for each one. 
-    println!("Server listening on {}", ip);
-    for stream in listener.incoming() {
-        // We only have to accept one client
-        match stream {
-            Ok(stream) => {
-                state.connection_status = ConnectionStatus::Connected;
-                println!("New connection: {}", stream.peer_addr().unwrap());
-
-                thread::spawn(move|| {
-                    println!("Connection succeeded");
-                    let _game_result = game::start_game(stream.try_clone().unwrap(), Server);
-                    println!("Shutting down stream");
-                    let _ = stream.shutdown(Shutdown::Both);
+    if Transport::from_config(&Config::load()) == Transport::Udp {
+        if !passphrase.is_empty() {
+            return Err(passphrase_needs_tcp().into());
+        }
+        return udp_server_main(&ip, profile, bot, progress);
+    }
+    let listener = TcpListener::bind(&ip).map_err(|e| Error::Bind(ip.clone(), e))?;
+    // We don't block on accepting, so that we notice when we're cancelled
+    listener.set_nonblocking(true)?;
+    info!("Server listening on {}", ip);
+    progress.report(ConnectionStatus::Listening { address: ip.clone(), joined: 0, needed: PLAYERS_NEEDED });
+
+    // Accept the first client that comes along and play one match with it.
+    // After that, the launcher decides what happens next. Anyone who leaves
+    // or gets the handshake wrong before the match starts is let go, and we
+    // wait for the next one. Spectators can come in before and during the
+    // match.
+    let spectators = Spectators::default();
+    while !progress.cancelled() {
+        if progress.filling_seat() {
+            drop(listener);
+            info!("Server stopped listening on {}", ip);
+            let result = play_bot(profile, bot, progress, &spectators);
+            spectators.close();
+            return result;
+        }
+        match listener.accept() {
+            Ok((stream, address)) => {
+                info!("New connection: {}", address);
+                let player = match greet(&stream) {
+                    Ok(Caller::Spectator(link)) => {
+                        let_watch(link, address, &passphrase, &spectators);
+                        continue;
+                    }
+                    Ok(Caller::Bot) => None,
+                    Ok(Caller::Player(link)) => Some(link),
+                    Err(e) => {
+                        warn!("Could not make out what {} wants: {}", address, e);
+                        continue;
+                    }
+                };
+                progress.report(ConnectionStatus::Listening {
+                    address: ip.clone(),
+                    joined: 1,
+                    needed: PLAYERS_NEEDED,
                 });
+
+                let done = Arc::new(AtomicBool::new(false));
+                let doorman = admit_spectators(listener.try_clone()?, spectators.clone(), passphrase.clone(), done.clone());
+                let result = serve(&stream, player, profile.clone(), &passphrase, progress, &spectators);
+                done.store(true, Ordering::Relaxed);
+                let _ = doorman.join();
+                info!("Shutting down stream");
+                let _ = stream.shutdown(Shutdown::Both);
+                match result {
+                    // Whoever it was never got as far as the match, so the
+                    // seat is still free
+                    Err(Error::Handshake(e)) if !progress.cancelled() => {
+                        warn!("{} couldn't join: {}", address, e);
+                        progress.report(ConnectionStatus::Listening {
+                            address: ip.clone(),
+                            joined: 0,
+                            needed: PLAYERS_NEEDED,
+                        });
+                    }
+                    result => {
+                        info!("Server stopped listening on {}", ip);
+                        spectators.close();
+                        return result;
+                    }
+                }
             },
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(CANCEL_POLL),
             Err(e) => {
                 // Connection failed
-                println!("Error: {}", e);
+                warn!("Could not accept a connection: {}", e);
             }
         }
     }
 
-    drop(listener);
+    info!("Server stopped listening on {}", ip);
+    spectators.close();
+    Ok(())
 }
 
-pub fn client_main(ip_address: String, port: String) {
+/// Who just connected to the server, going by what they said first
+enum Caller {
+    /// A bot that speaks the bot API
+    Bot,
+    /// A game client that wants to play
+    Player(Box<dyn Link>),
+    /// Someone who only wants to watch
+    Spectator(Box<dyn Link>),
+}
+
+/// Find out who just connected. Bots speak JSON, so the first thing they
+/// send is a `{`. A WebSocket client starts with the `GET` of its handshake,
+/// and asks for `WATCH_PATH` if it only wants to watch. Game clients start
+/// with their ready message or, if the match has a passphrase, with
+/// `secure::AUTH_MARKER`.
+fn greet(stream: &TcpStream) -> io::Result<Caller> {
+    stream.set_nonblocking(false)?;
+    // Someone who connects and says nothing doesn't get to hold us up
+    stream.set_read_timeout(Some(GREET_TIMEOUT))?;
+    let mut first = [0; 1];
+    stream.peek(&mut first)?;
+
+    if first[0] == b'G' {
+        let (link, path) = WebSocketLink::accept(simulated(stream.try_clone()?)?)?;
+        if path == WATCH_PATH {
+            // A spectator that stops reading mustn't keep its sender waiting
+            // forever
+            link.set_write_timeout(Some(SPECTATOR_WRITE_TIMEOUT))?;
+            return Ok(Caller::Spectator(Box::new(link)));
+        }
+        return Ok(Caller::Player(Box::new(link)));
+    }
+
+    if first[0] == b'{' {
+        // It still has to finish its hello in time, see `RemoteBot::over_tcp`
+        return Ok(Caller::Bot);
+    }
+    stream.set_read_timeout(None)?;
+    Ok(Caller::Player(tcp_link(stream.try_clone()?)?))
+}
+
+/// Let a spectator watch, once it has shown that it knows the passphrase
+fn let_watch(link: Box<dyn Link>, address: SocketAddr, passphrase: &str, spectators: &Spectators) {
+    match secured(link, Server, passphrase) {
+        Ok(link) => {
+            info!("{} is watching", address);
+            spectators.add(link);
+        }
+        Err(e) => warn!("Spectator {} couldn't join: {}", address, e),
+    }
+}
+
+/// While the match is on, keep letting spectators in until it's `done`.
+/// Anyone else is turned away, since both seats are taken.
+fn admit_spectators(
+    listener: TcpListener,
+    spectators: Spectators,
+    passphrase: String,
+    done: Arc<AtomicBool>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        while !done.load(Ordering::Relaxed) {
+            match listener.accept() {
+                Ok((stream, address)) => match greet(&stream) {
+                    Ok(Caller::Spectator(link)) => let_watch(link, address, &passphrase, &spectators),
+                    Ok(_) => {
+                        info!("Turned {} away, since the match already started", address);
+                        let _ = stream.shutdown(Shutdown::Both);
+                    }
+                    Err(e) => warn!("Could not make out what {} wants: {}", address, e),
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(CANCEL_POLL),
+                Err(e) => warn!("Could not accept a connection: {}", e),
+            }
+        }
+    })
+}
+
+/// Play a match with whoever just connected to the server: the game client
+/// at the other end of `player`, or a bot if there's no link. If they don't
+/// make it as far as the match, that's an `Error::Handshake`.
+fn serve(
+    stream: &TcpStream,
+    player: Option<Box<dyn Link>>,
+    profile: Profile,
+    passphrase: &str,
+    progress: &Progress,
+    spectators: &Spectators,
+) -> Result<(), Error> {
+    progress.report(ConnectionStatus::Handshake);
+
+    let link = match player {
+        Some(link) => link,
+        None => {
+            // The bot API has no way to prove it knows the passphrase
+            if !passphrase.is_empty() {
+                return Err(Error::handshake(secure::refused("bots can't join a match with a passphrase")));
+            }
+            let remote_bot = stream.try_clone().and_then(RemoteBot::over_tcp).map_err(Error::handshake)?;
+            info!("Bot {} joined", remote_bot.name());
+            progress.report(ConnectionStatus::InGame);
+            let stop = || progress.cancelled();
+            return game::start_game(None, Local, profile, None, Some(remote_bot), &stop, spectators.clone());
+        }
+    };
+    let link = secured(link, Server, passphrase).map_err(Error::handshake)?;
+    if progress.cancelled() {
+        return Ok(());
+    }
+    progress.report(ConnectionStatus::InGame);
+    game::start_game(Some(link), Server, profile, None, None, &|| progress.cancelled(), spectators.clone())
+}
+
+/// Nobody joined the server, so its player plays the bot in the seat that's
+/// still empty, the same way as in a local game
+fn play_bot(
+    profile: Profile,
+    bot: Option<Difficulty>,
+    progress: &Progress,
+    spectators: &Spectators,
+) -> Result<(), Error> {
+    info!("Filling the empty seat with a bot");
+    progress.report(ConnectionStatus::InGame);
+    game::start_game(None, Local, profile, bot, None, &|| false, spectators.clone())
+}
+
+/// There's no listening over UDP, so the server waits for one client,
+/// which has the port to itself until the game is over, or until it leaves
+/// before the match starts.
+fn udp_server_main(ip: &str, profile: Profile, bot: Option<Difficulty>, progress: &Progress) -> Result<(), Error> {
+    loop {
+        info!("Server waiting for a UDP client on {}", ip);
+        progress.report(ConnectionStatus::Listening { address: ip.to_string(), joined: 0, needed: PLAYERS_NEEDED });
+        let link = match UdpLink::accept(ip, || progress.cancelled() || progress.filling_seat()).and_then(simulated_udp) {
+            Ok(link) => link,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted && progress.filling_seat() => {
+                return play_bot(profile.clone(), bot, progress, &Spectators::default());
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => return Ok(()),
+            Err(e) => return Err(Error::Bind(ip.to_string(), e)),
+        };
+        info!("Connection succeeded");
+        progress.report(ConnectionStatus::InGame);
+        let stop = || progress.cancelled();
+        match game::start_game(Some(Box::new(link)), Server, profile.clone(), None, None, &stop, Spectators::default()) {
+            Err(Error::Handshake(e)) if !progress.cancelled() => warn!("The client couldn't join: {}", e),
+            result => return result,
+        }
+    }
+}
+
+fn passphrase_needs_tcp() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "a match with a passphrase needs the TCP transport")
+}
+
+pub fn client_main(
+    ip_address: String,
+    port: String,
+    profile: Profile,
+    bot: Option<Difficulty>,
+    passphrase: String,
+    progress: &Progress,
+) -> Result<(), Error> {
     let ip = [ip_address, port].join(":");
+    progress.report(ConnectionStatus::Connecting(ip.clone()));
+
+    let link: Box<dyn Link> = match Transport::from_config(&Config::load()) {
+        Transport::Tcp => TcpStream::connect(&ip)
+            .and_then(tcp_link)
+            .map_err(|e| Error::Connect(ip.clone(), e))?,
+        Transport::Udp if !passphrase.is_empty() => return Err(passphrase_needs_tcp().into()),
+        Transport::Udp => Box::new(
+            UdpLink::connect(&ip)
+                .and_then(simulated_udp)
+                .map_err(|e| Error::Connect(ip.clone(), e))?,
+        ),
+    };
+    if progress.cancelled() {
+        info!("Connected to {}, but we were cancelled", ip);
+        return Ok(());
+    }
+    progress.report(ConnectionStatus::Handshake);
+    let link = secured(link, Client, &passphrase)?;
+    if progress.cancelled() {
+        info!("Connected to {}, but we were cancelled", ip);
+        return Ok(());
+    }
+
+    info!("Successfully connected to server at {}", ip);
+    progress.report(ConnectionStatus::InGame);
+    let stop = || progress.cancelled();
+    let result = game::start_game(Some(link), Client, profile, bot, None, &stop, Spectators::default());
+    info!("Shutting down stream");
+    result
+}
+
+/// Play a hot-seat game where both players share this machine's keyboard.
+pub fn local_main(profile: Profile, bot: Option<Difficulty>) -> Result<(), Error> {
+    game::start_game(None, Local, profile, bot, None, &|| false, Spectators::default())
+}
+
+/// Play a single-player game and try to make it into the high-score table.
+pub fn single_main(profile: Profile, bot: Option<Difficulty>) -> Result<(), Error> {
+    game::start_game(None, Single, profile, bot, None, &|| false, Spectators::default())
+}
 
-    match TcpStream::connect(&ip) {
-        Ok(mut stream) => {
-            println!("Successfully connected to server at {}", ip);
-            let _game_result = game::start_game(stream, Client);
-            println!("Shutting down stream");
-        },
-        Err(e) => {
-            println!("Failed to connect: {}", e);
-        },
-    }
-}
\ No newline at end of file
+/// Play on with the game that was saved from the pause menu, if there is one.
+pub fn resume_main(profile: Profile, bot: Option<Difficulty>) -> Result<(), Error> {
+    match SavedGame::take() {
+        Some(saved) => game::resume_game(saved, profile, bot),
+        None => Err(io::Error::new(io::ErrorKind::NotFound, "there's no saved game to resume").into()),
+    }
+}
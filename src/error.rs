@@ -0,0 +1,82 @@
+//! Everything that can go wrong between pressing a button in the launcher
+//! and the end of the match, in a way that we can show to whoever pressed it.
+
+use ggez::GameError;
+
+use std::fmt;
+use std::io;
+
+use super::game::ProtocolError;
+
+#[derive(Debug)]
+pub enum Error {
+    /// We couldn't listen on the address we were given
+    Bind(String, io::Error),
+    /// We couldn't reach the server at the address we were given
+    Connect(String, io::Error),
+    /// The other player sent something that can't be right
+    Protocol(ProtocolError),
+    /// The other player hung up or stopped answering
+    PeerGone(String),
+    /// Someone connected, but left or got something wrong before the match
+    /// started
+    Handshake(Box<Error>),
+    /// The game window couldn't be opened or drawn
+    Graphics(GameError),
+    /// Anything else that went wrong on the way
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Bind(address, e) => write!(f, "Could not listen on {}: {}", address, e),
+            Error::Connect(address, e) => write!(f, "Could not connect to {}: {}", address, e),
+            Error::Protocol(e) => write!(f, "The other player sent bad data: {}", e),
+            Error::PeerGone(reason) => write!(f, "Lost the other player: {}", reason),
+            Error::Handshake(e) => write!(f, "Could not start the match: {}", e),
+            Error::Graphics(e) => write!(f, "Could not show the game: {}", e),
+            Error::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Error {
+    /// Whatever went wrong before the match started
+    pub fn handshake(e: impl Into<Error>) -> Self {
+        Error::Handshake(Box::new(e.into()))
+    }
+}
+
+impl From<io::Error> for Error {
+    /// Sort out what an error from a link means. Protocol errors travel
+    /// inside I/O errors, and a connection that ends early means the other
+    /// player left.
+    fn from(e: io::Error) -> Self {
+        if let Some(protocol) = e.get_ref().and_then(|inner| inner.downcast_ref::<ProtocolError>()) {
+            return Error::Protocol(*protocol);
+        }
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::TimedOut => Error::PeerGone(e.to_string()),
+            _ => Error::Io(e),
+        }
+    }
+}
+
+impl From<ProtocolError> for Error {
+    fn from(e: ProtocolError) -> Self {
+        Error::Protocol(e)
+    }
+}
+
+impl From<GameError> for Error {
+    fn from(e: GameError) -> Self {
+        Error::Graphics(e)
+    }
+}
@@ -0,0 +1,138 @@
+use druid::Data;
+use ggez::event::KeyCode;
+
+use super::Direction;
+use crate::config::Config;
+
+/// The keys that can be bound to a direction. We look keys up by their
+/// `KeyCode` name, so `W`, `Up` and `Numpad8` are all valid in the config.
+const BINDABLE_KEYS: &[KeyCode] = &[
+    KeyCode::A, KeyCode::B, KeyCode::C, KeyCode::D, KeyCode::E, KeyCode::F,
+    KeyCode::G, KeyCode::H, KeyCode::I, KeyCode::J, KeyCode::K, KeyCode::L,
+    KeyCode::M, KeyCode::N, KeyCode::O, KeyCode::P, KeyCode::Q, KeyCode::R,
+    KeyCode::S, KeyCode::T, KeyCode::U, KeyCode::V, KeyCode::W, KeyCode::X,
+    KeyCode::Y, KeyCode::Z,
+    KeyCode::Key0, KeyCode::Key1, KeyCode::Key2, KeyCode::Key3, KeyCode::Key4,
+    KeyCode::Key5, KeyCode::Key6, KeyCode::Key7, KeyCode::Key8, KeyCode::Key9,
+    KeyCode::Numpad0, KeyCode::Numpad1, KeyCode::Numpad2, KeyCode::Numpad3,
+    KeyCode::Numpad4, KeyCode::Numpad5, KeyCode::Numpad6, KeyCode::Numpad7,
+    KeyCode::Numpad8, KeyCode::Numpad9,
+    KeyCode::Up, KeyCode::Down, KeyCode::Left, KeyCode::Right,
+    KeyCode::Space, KeyCode::Comma, KeyCode::Period, KeyCode::Semicolon,
+];
+
+/// Look up a key by its name, ignoring case.
+pub fn parse_keycode(name: &str) -> Option<KeyCode> {
+    BINDABLE_KEYS
+        .iter()
+        .find(|key| format!("{:?}", key).eq_ignore_ascii_case(name))
+        .copied()
+}
+
+/// The preset layouts a player can pick from before rebinding single keys.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Data)]
+pub enum Layout {
+    Arrows,
+    Wasd,
+    Hjkl,
+    Numpad,
+}
+
+impl Layout {
+    pub const ALL: [Layout; 4] = [Layout::Arrows, Layout::Wasd, Layout::Hjkl, Layout::Numpad];
+
+    pub fn from_name(name: &str) -> Option<Layout> {
+        match name.to_ascii_lowercase().as_str() {
+            "arrows" => Some(Layout::Arrows),
+            "wasd"   => Some(Layout::Wasd),
+            "hjkl"   => Some(Layout::Hjkl),
+            "numpad" => Some(Layout::Numpad),
+            _        => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Layout::Arrows => "arrows",
+            Layout::Wasd   => "wasd",
+            Layout::Hjkl   => "hjkl",
+            Layout::Numpad => "numpad",
+        }
+    }
+
+    /// The layout that comes after this one in the launcher
+    pub fn cycle(self) -> Layout {
+        let index = Self::ALL.iter().position(|layout| *layout == self).unwrap_or(0);
+        Self::ALL[(index + 1) % Self::ALL.len()]
+    }
+
+    /// The layout `player` picked in the config, or their default one
+    pub fn from_config(config: &Config, player: u8) -> Layout {
+        config
+            .get(&format!("keys{}.layout", player))
+            .and_then(Layout::from_name)
+            .unwrap_or(if player == 1 { Layout::Arrows } else { Layout::Wasd })
+    }
+}
+
+/// Which key moves the snake in which direction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyBindings {
+    pub up: KeyCode,
+    pub down: KeyCode,
+    pub left: KeyCode,
+    pub right: KeyCode,
+}
+
+impl KeyBindings {
+    pub fn from_layout(layout: Layout) -> Self {
+        let (up, down, left, right) = match layout {
+            Layout::Arrows => (KeyCode::Up, KeyCode::Down, KeyCode::Left, KeyCode::Right),
+            Layout::Wasd   => (KeyCode::W, KeyCode::S, KeyCode::A, KeyCode::D),
+            Layout::Hjkl   => (KeyCode::K, KeyCode::J, KeyCode::H, KeyCode::L),
+            Layout::Numpad => (KeyCode::Numpad8, KeyCode::Numpad2, KeyCode::Numpad4, KeyCode::Numpad6),
+        };
+
+        KeyBindings { up, down, left, right }
+    }
+
+    /// Read the bindings of a keyboard player from the config. The first
+    /// player (`keys1`) is the one sitting at this machine in a networked
+    /// game and defaults to the arrow keys, while the second player (`keys2`)
+    /// defaults to WASD. A player picks a layout with `keysN.layout` and
+    /// can then rebind single keys with `keysN.up`, `keysN.down`,
+    /// `keysN.left` and `keysN.right`.
+    pub fn from_config(config: &Config, player: u8) -> Self {
+        let prefix = format!("keys{}", player);
+        let mut bindings = Self::from_layout(Layout::from_config(config, player));
+
+        let key = |name: &str| {
+            config
+                .get(&format!("{}.{}", prefix, name))
+                .and_then(parse_keycode)
+        };
+        if let Some(k) = key("up")    { bindings.up = k; }
+        if let Some(k) = key("down")  { bindings.down = k; }
+        if let Some(k) = key("left")  { bindings.left = k; }
+        if let Some(k) = key("right") { bindings.right = k; }
+
+        bindings
+    }
+
+    /// Convert a `ggez` KeyCode into the Direction it is bound to. Of course,
+    /// not every keycode is bound to a direction, so we return `None` if
+    /// this is the case.
+    pub fn direction(&self, key: KeyCode) -> Option<Direction> {
+        if key == self.up {
+            Some(Direction::Up)
+        } else if key == self.down {
+            Some(Direction::Down)
+        } else if key == self.left {
+            Some(Direction::Left)
+        } else if key == self.right {
+            Some(Direction::Right)
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::VecDeque;
+
+use super::{Direction, Food, GridPosition, Pilot, Snake, GRID_SIZE};
+use crate::Difficulty;
+
+/// Every direction a snake could go, in the order the bots try them
+const DIRECTIONS: [Direction; 4] = [
+    Direction::Up,
+    Direction::Down,
+    Direction::Left,
+    Direction::Right,
+];
+
+/// A computer player. Each tick it looks at the board and picks a direction
+/// to steer in, which goes through `Snake::steer` just like a key press, so
+/// a bot can take the place of a human in any game.
+#[derive(Clone, Copy, Debug)]
+pub struct Bot {
+    difficulty: Difficulty,
+}
+
+/// Which cells of the board are taken by a snake
+struct Board {
+    blocked: Vec<bool>,
+}
+
+impl Board {
+    fn new(snakes: &[&Snake]) -> Self {
+        let mut board = Board {
+            blocked: vec![false; GRID_SIZE.0 as usize * GRID_SIZE.1 as usize],
+        };
+
+        for snake in snakes {
+            board.block(snake.head.pos);
+            for seg in snake.body.iter() {
+                board.block(seg.pos);
+            }
+        }
+        board
+    }
+
+    fn index(pos: GridPosition) -> usize {
+        pos.y as usize * GRID_SIZE.0 as usize + pos.x as usize
+    }
+
+    fn block(&mut self, pos: GridPosition) {
+        self.blocked[Self::index(pos)] = true;
+    }
+
+    fn is_free(&self, pos: GridPosition) -> bool {
+        !self.blocked[Self::index(pos)]
+    }
+}
+
+/// How many moves it takes to get from `a` to `b`, keeping in mind that
+/// the board wraps around at the edges.
+fn distance(a: GridPosition, b: GridPosition) -> i16 {
+    let dx = (a.x - b.x).abs();
+    let dy = (a.y - b.y).abs();
+
+    dx.min(GRID_SIZE.0 - dx) + dy.min(GRID_SIZE.1 - dy)
+}
+
+impl Bot {
+    pub fn new(difficulty: Difficulty) -> Self {
+        Bot { difficulty }
+    }
+
+    /// Pick the direction `me` should go in next. `other` is the opponent's
+    /// snake, if there is one. Returns `None` if there's no point in
+    /// steering, i.e. every move is as bad as every other.
+    pub fn plan(&self, me: &Snake, other: Option<&Snake>, food: &Food) -> Option<Direction> {
+        let mut snakes = vec![me];
+        snakes.extend(other);
+        let board = Board::new(&snakes);
+
+        // Whatever we do, we can't turn back on ourselves
+        let moves: Vec<Direction> = DIRECTIONS
+            .iter()
+            .copied()
+            .filter(|dir| *dir != me.last_update_dir.inverse())
+            .collect();
+
+        match self.difficulty {
+            // The easy bot heads straight for the food, even if that means
+            // running into a snake
+            Difficulty::Easy => Self::greedy(me, food, moves.iter().copied()),
+            // The medium bot does the same, but won't make a move that
+            // runs into a snake right away
+            Difficulty::Medium => {
+                let safe = Self::safe_moves(me, &board, &moves);
+                Self::greedy(me, food, safe.into_iter())
+            }
+            // and the hard bot plans the shortest way around both snakes
+            Difficulty::Hard => {
+                Self::shortest_path(me, food, &board, &moves)
+                    .or_else(|| Self::most_room(me, &board, &moves))
+            }
+        }
+    }
+
+    /// The moves that don't run into a snake on the next tick
+    fn safe_moves(me: &Snake, board: &Board, moves: &[Direction]) -> Vec<Direction> {
+        moves
+            .iter()
+            .copied()
+            .filter(|dir| board.is_free(GridPosition::new_from_move(me.head.pos, *dir)))
+            .collect()
+    }
+
+    /// The move that gets us closest to the food
+    fn greedy(me: &Snake, food: &Food, moves: impl Iterator<Item = Direction>) -> Option<Direction> {
+        moves.min_by_key(|dir| distance(GridPosition::new_from_move(me.head.pos, *dir), food.pos))
+    }
+
+    /// The first move on the shortest path to the food that doesn't go
+    /// through any snake, found with a breadth-first search.
+    fn shortest_path(me: &Snake, food: &Food, board: &Board, moves: &[Direction]) -> Option<Direction> {
+        let mut visited = vec![false; board.blocked.len()];
+        let mut queue = VecDeque::new();
+
+        visited[Board::index(me.head.pos)] = true;
+        for dir in Self::safe_moves(me, board, moves) {
+            let pos = GridPosition::new_from_move(me.head.pos, dir);
+            visited[Board::index(pos)] = true;
+            queue.push_back((pos, dir));
+        }
+
+        while let Some((pos, first_move)) = queue.pop_front() {
+            if pos == food.pos {
+                return Some(first_move);
+            }
+
+            for dir in DIRECTIONS.iter() {
+                let next = GridPosition::new_from_move(pos, *dir);
+                if board.is_free(next) && !visited[Board::index(next)] {
+                    visited[Board::index(next)] = true;
+                    queue.push_back((next, first_move));
+                }
+            }
+        }
+
+        None
+    }
+
+    /// When we can't get to the food, we go wherever leaves us the most
+    /// free cells to move around in, hoping the way opens up later.
+    fn most_room(me: &Snake, board: &Board, moves: &[Direction]) -> Option<Direction> {
+        Self::safe_moves(me, board, moves)
+            .into_iter()
+            .max_by_key(|dir| Self::room(GridPosition::new_from_move(me.head.pos, *dir), board))
+    }
+
+    /// Count the free cells we can reach from `start`
+    fn room(start: GridPosition, board: &Board) -> usize {
+        let mut visited = vec![false; board.blocked.len()];
+        let mut stack = vec![start];
+        let mut count = 0;
+
+        visited[Board::index(start)] = true;
+        while let Some(pos) = stack.pop() {
+            count += 1;
+            for dir in DIRECTIONS.iter() {
+                let next = GridPosition::new_from_move(pos, *dir);
+                if board.is_free(next) && !visited[Board::index(next)] {
+                    visited[Board::index(next)] = true;
+                    stack.push(next);
+                }
+            }
+        }
+
+        count
+    }
+}
+
+impl Pilot for Bot {
+    fn next_direction(&mut self, me: &Snake, other: Option<&Snake>, food: &Food, _tick: u128) -> Option<Direction> {
+        self.plan(me, other, food)
+    }
+}
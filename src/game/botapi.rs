@@ -0,0 +1,398 @@
+//! The bot API lets programs written in any language play a snake. A bot
+//! connects to the server's port like a game client does, but speaks
+//! line-delimited JSON instead: every message is one JSON object on its own
+//! line. The server tells the two apart by the first byte it receives.
+//!
+//! 1. The bot says hello, optionally giving its name:
+//!    `{"hello": "snakes-bot", "name": "greedy"}`
+//! 2. The server tells the bot which snake it plays, the size of the
+//!    board, and how long it has to answer each tick:
+//!    `{"type": "welcome", "you": 2, "width": 30, "height": 20, "deadline_ms": 100}`
+//! 3. On every tick of the match, the server sends the board:
+//!    `{"type": "tick", "tick": 25, "food": [3, 7], "you": SNAKE, "opponent": SNAKE}`
+//!    where a `SNAKE` is `{"head": [x, y], "body": [[x, y], ...], "dir": "right"}`
+//!    and `opponent` is `null` if there isn't one.
+//! 4. The bot answers with the direction it wants to go in, echoing the tick:
+//!    `{"tick": 25, "dir": "up"}`
+//!    Directions are `"up"`, `"down"`, `"left"` and `"right"`. If no answer
+//!    for the current tick arrives before the deadline, the bot's last move
+//!    is repeated, and answers that arrive too late are ignored. In the game
+//!    window, which doesn't wait for the bot, the answer steers the snake
+//!    on the tick after.
+//! 5. Once the match is over, the server sends
+//!    `{"type": "end", "result": "win"}`, where the result is `"win"`,
+//!    `"lose"` or `"draw"`, and closes the connection.
+//!
+//! Coordinates start at `[0, 0]` in the top left corner, and the board wraps
+//! around at the edges.
+
+use serde_json::{json, Value};
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::json::{direction_from_name, direction_name, position_to_json};
+use super::{Direction, Food, Pilot, Player, Snake, GRID_SIZE};
+
+/// How long a bot has to answer each tick
+const BOT_DEADLINE: Duration = Duration::from_millis(100);
+/// How long a bot has to say hello
+const HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+/// The longest line we take from a bot, which any message of the bot API
+/// fits in with plenty of room to spare
+const MAX_LINE: u64 = 4096;
+
+/// Something a bot did that means it can't play
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BotError {
+    /// It didn't say hello in time
+    Silent,
+    /// It sent a line longer than `MAX_LINE`
+    LineTooLong,
+    /// Its first line wasn't a hello
+    NoHello,
+}
+
+impl fmt::Display for BotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BotError::Silent => write!(f, "the bot didn't say hello in time"),
+            BotError::LineTooLong => write!(f, "the bot sent a line longer than {} bytes", MAX_LINE),
+            BotError::NoHello => write!(f, "expected a hello from the bot"),
+        }
+    }
+}
+
+impl Error for BotError {}
+
+impl From<BotError> for io::Error {
+    fn from(e: BotError) -> Self {
+        let kind = match e {
+            BotError::Silent => io::ErrorKind::TimedOut,
+            _ => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, e)
+    }
+}
+
+/// A snake played by a program on the other end of a connection
+pub struct RemoteBot {
+    name: String,
+    /// The lines the bot sends us, read on their own thread so that we
+    /// can stop waiting once the deadline has passed
+    lines: Receiver<io::Result<String>>,
+    writer: Box<dyn Write + Send>,
+    /// The last direction the bot asked for, which we repeat when it
+    /// doesn't answer in time
+    last_dir: Option<Direction>,
+}
+
+impl RemoteBot {
+    /// Wait for a bot's hello on `reader`, like one we started that talks
+    /// to us through pipes. We answer it on `writer`.
+    pub fn new<R, W>(reader: R, writer: W) -> io::Result<Self>
+    where
+        R: Read + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        // A pipe can't time out, so we wait for the hello on the thread
+        // that reads everything after it too
+        let mut bot = Self::listen(String::new(), BufReader::new(reader), writer);
+        bot.name = match bot.lines.recv_timeout(HELLO_TIMEOUT) {
+            Ok(hello) => parse_hello(&hello?)?,
+            Err(RecvTimeoutError::Timeout) => return Err(BotError::Silent.into()),
+            Err(RecvTimeoutError::Disconnected) => return Err(io::ErrorKind::UnexpectedEof.into()),
+        };
+        Ok(bot)
+    }
+
+    /// A bot that connected to us over TCP
+    pub fn over_tcp(stream: TcpStream) -> io::Result<Self> {
+        // A bot that connects and says nothing doesn't get to hold us up,
+        // but once it's in, it has the deadline of every tick instead
+        stream.set_read_timeout(Some(HELLO_TIMEOUT))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let name = read_hello(&mut reader)?;
+        stream.set_read_timeout(None)?;
+        Ok(Self::listen(name, reader, stream))
+    }
+
+    /// Start reading the lines of a bot that said hello
+    fn listen<R, W>(name: String, mut reader: BufReader<R>, writer: W) -> Self
+    where
+        R: Read + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        let (sender, lines) = mpsc::channel();
+        thread::spawn(move || {
+            // We stop once either the bot hung up or sent something that
+            // can't be an answer, or we don't care anymore
+            loop {
+                let line = match read_line(&mut reader) {
+                    Ok(Some(line)) => Ok(line),
+                    Ok(None) => break,
+                    Err(e) => Err(e),
+                };
+                let failed = line.is_err();
+                if sender.send(line).is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        RemoteBot {
+            name,
+            lines,
+            writer: Box::new(writer),
+            last_dir: None,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Play the bot on a thread of its own, for a game window that can't
+    /// wait on it
+    pub fn in_background(self) -> BackgroundBot {
+        // One board at a time: if the bot is still thinking about the last
+        // one, it doesn't get this one
+        let (sender, requests) = mpsc::sync_channel(1);
+        let (answers, directions) = mpsc::channel();
+        let mut bot = self;
+        thread::spawn(move || {
+            for request in requests {
+                match request {
+                    Request::Start(player) => bot.start(player),
+                    Request::Tick { me, other, food, tick } => {
+                        let dir = bot.next_direction(&me, other.as_ref(), &food, tick);
+                        if answers.send(dir).is_err() {
+                            break;
+                        }
+                    }
+                    Request::GameOver(won) => bot.game_over(won),
+                }
+            }
+        });
+
+        BackgroundBot {
+            requests: sender,
+            directions,
+            last_dir: None,
+        }
+    }
+
+    fn send(&mut self, message: Value) {
+        // If the bot is gone, it just stops answering and its snake keeps
+        // going in the same direction
+        let _ = writeln!(self.writer, "{}", message).and_then(|_| self.writer.flush());
+    }
+
+    /// Wait for the bot's answer for `tick`, until the deadline passes.
+    fn receive_direction(&mut self, tick: u128) -> Option<Direction> {
+        let deadline = Instant::now() + BOT_DEADLINE;
+
+        loop {
+            let left = deadline.checked_duration_since(Instant::now())?;
+            let line = match self.lines.recv_timeout(left).ok()? {
+                Ok(line) => line,
+                Err(_) => continue,
+            };
+
+            let answer: Value = match serde_json::from_str(&line) {
+                Ok(answer) => answer,
+                Err(_)     => continue,
+            };
+            if answer.get("tick").and_then(Value::as_u64) != Some(tick as u64) {
+                // An answer to a tick we already gave up on
+                continue;
+            }
+            if let Some(dir) = answer.get("dir").and_then(Value::as_str).and_then(direction_from_name) {
+                return Some(dir);
+            }
+        }
+    }
+}
+
+impl Pilot for RemoteBot {
+    fn start(&mut self, player: Player) {
+        let you = match player {
+            Player::One => 1,
+            Player::Two => 2,
+        };
+        self.send(json!({
+            "type": "welcome",
+            "you": you,
+            "width": GRID_SIZE.0,
+            "height": GRID_SIZE.1,
+            "deadline_ms": BOT_DEADLINE.as_millis() as u64,
+        }));
+    }
+
+    fn next_direction(&mut self, me: &Snake, other: Option<&Snake>, food: &Food, tick: u128) -> Option<Direction> {
+        self.send(json!({
+            "type": "tick",
+            "tick": tick as u64,
+            "food": position_to_json(food.pos),
+            "you": snake_to_json(me),
+            "opponent": other.map(snake_to_json),
+        }));
+
+        if let Some(dir) = self.receive_direction(tick) {
+            self.last_dir = Some(dir);
+        }
+        self.last_dir
+    }
+
+    fn game_over(&mut self, won: Option<bool>) {
+        let result = match won {
+            Some(true)  => "win",
+            Some(false) => "lose",
+            None        => "draw",
+        };
+        self.send(json!({ "type": "end", "result": result }));
+    }
+}
+
+/// A `RemoteBot` playing on its own thread. It answers for a tick while the
+/// game goes on, so its answer only steers the snake on the tick after.
+pub struct BackgroundBot {
+    requests: SyncSender<Request>,
+    directions: Receiver<Option<Direction>>,
+    last_dir: Option<Direction>,
+}
+
+/// What the game asks of a `BackgroundBot`
+enum Request {
+    Start(Player),
+    Tick { me: Snake, other: Option<Snake>, food: Food, tick: u128 },
+    GameOver(Option<bool>),
+}
+
+impl Pilot for BackgroundBot {
+    fn start(&mut self, player: Player) {
+        // Nothing else was asked yet, so there's room
+        let _ = self.requests.try_send(Request::Start(player));
+    }
+
+    fn next_direction(&mut self, me: &Snake, other: Option<&Snake>, food: &Food, tick: u128) -> Option<Direction> {
+        // Whatever the bot made of the last board it saw
+        while let Ok(dir) = self.directions.try_recv() {
+            if dir.is_some() {
+                self.last_dir = dir;
+            }
+        }
+        let _ = self.requests.try_send(Request::Tick {
+            me: me.clone(),
+            other: other.cloned(),
+            food: food.clone(),
+            tick,
+        });
+        self.last_dir
+    }
+
+    fn game_over(&mut self, won: Option<bool>) {
+        // A bot that's still busy with the last board misses how it ended,
+        // but it still sees us hang up once the window closes
+        let _ = self.requests.try_send(Request::GameOver(won));
+    }
+}
+
+/// Read the bot's hello, and the name it gave, if any
+fn read_hello(reader: &mut impl BufRead) -> io::Result<String> {
+    let hello = match read_line(reader) {
+        Ok(Some(hello)) => hello,
+        Ok(None) => return Err(io::ErrorKind::UnexpectedEof.into()),
+        Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+            return Err(BotError::Silent.into());
+        }
+        Err(e) => return Err(e),
+    };
+    parse_hello(&hello)
+}
+
+/// The name a bot gave in its hello, if any
+fn parse_hello(hello: &str) -> io::Result<String> {
+    let hello: Value = serde_json::from_str(hello).map_err(|_| BotError::NoHello)?;
+    if hello.get("hello").is_none() {
+        return Err(BotError::NoHello.into());
+    }
+    Ok(hello
+        .get("name")
+        .and_then(Value::as_str)
+        .unwrap_or("bot")
+        .to_string())
+}
+
+/// Read the next line the bot sent, or `None` if it hung up
+fn read_line(reader: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut line = String::new();
+    let read = reader.take(MAX_LINE).read_line(&mut line)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if read as u64 == MAX_LINE && !line.ends_with('\n') {
+        return Err(BotError::LineTooLong.into());
+    }
+    Ok(Some(line))
+}
+
+fn snake_to_json(snake: &Snake) -> Value {
+    let body: Vec<Value> = snake.body.iter().map(|seg| position_to_json(seg.pos)).collect();
+
+    json!({
+        "head": position_to_json(snake.head.pos),
+        "body": body,
+        "dir": direction_name(snake.dir),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use crate::game::GridPosition;
+
+    fn hello(lines: &str) -> io::Result<String> {
+        RemoteBot::new(Cursor::new(lines.to_string()), io::sink()).map(|bot| bot.name)
+    }
+
+    fn bot_error(result: io::Result<String>) -> Option<BotError> {
+        result.err()?.get_ref()?.downcast_ref::<BotError>().copied()
+    }
+
+    #[test]
+    fn bots_say_hello_with_their_name() {
+        assert_eq!(hello("{\"hello\": \"snakes-bot\", \"name\": \"greedy\"}\n").unwrap(), "greedy");
+        assert_eq!(hello("{\"hello\": \"snakes-bot\"}\n").unwrap(), "bot");
+    }
+
+    #[test]
+    fn bots_that_dont_say_hello_are_turned_away() {
+        assert_eq!(bot_error(hello("{\"tick\": 1, \"dir\": \"up\"}\n")), Some(BotError::NoHello));
+        assert_eq!(bot_error(hello(&"x".repeat(MAX_LINE as usize + 1))), Some(BotError::LineTooLong));
+        assert_eq!(hello("").unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn bots_in_the_background_answer_on_the_next_tick() {
+        let lines = "{\"hello\": \"snakes-bot\"}\n{\"tick\": 1, \"dir\": \"up\"}\n";
+        let mut bot = RemoteBot::new(Cursor::new(lines.to_string()), io::sink()).unwrap().in_background();
+        let me = Snake::new(GridPosition::new(5, 5));
+        let food = Food::new(GridPosition::new(3, 7));
+
+        let asked = Instant::now();
+        assert_eq!(bot.next_direction(&me, None, &food, 1), None);
+        assert!(asked.elapsed() < BOT_DEADLINE);
+
+        thread::sleep(BOT_DEADLINE);
+        assert_eq!(bot.next_direction(&me, None, &food, 2), Some(Direction::Up));
+    }
+}
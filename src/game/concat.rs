@@ -1,4 +1,10 @@
-use super::Direction;
+use super::messages::ProtocolError;
+use super::profile::{clean_name, Colour, Profile, MAX_NAME_BYTES};
+use super::skin::Skin;
+use super::{Direction, BUFFER_SIZE, MAX_INTENTS};
+
+/// Marker byte a peer sends once its window is up and it is ready to play.
+const READY: u8 = 0xAA;
 
 pub fn concat_i16_i16(arr1: &[u8], arr2: &[u8]) -> [u8; 4] {
     let mut b = [0; 4];
@@ -14,7 +20,7 @@ pub fn concat_i16_i16(arr1: &[u8], arr2: &[u8]) -> [u8; 4] {
     b
 }
 
-pub fn add_position(buffer: &mut [u8; 8], position: &[u8; 4]) -> [u8; 8] {
+pub fn add_position(buffer: &mut [u8; BUFFER_SIZE], position: &[u8; 4]) -> [u8; BUFFER_SIZE] {
     for i in 4..8 {
         buffer[i] = position[i - 4];
     }
@@ -22,7 +28,7 @@ pub fn add_position(buffer: &mut [u8; 8], position: &[u8; 4]) -> [u8; 8] {
     buffer.clone()
 }
 
-pub fn read_position(buffer: &[u8; 8]) -> [u8; 4] {
+pub fn read_position(buffer: &[u8; BUFFER_SIZE]) -> [u8; 4] {
     let mut b = [0; 4];
 
     for i in 4..8 {
@@ -31,7 +37,7 @@ pub fn read_position(buffer: &[u8; 8]) -> [u8; 4] {
     b.clone()
 }
 
-pub fn is_game_over(buffer: &mut [u8; 8], is_game_over: bool) -> [u8; 8] {
+pub fn is_game_over(buffer: &mut [u8; BUFFER_SIZE], is_game_over: bool) -> [u8; BUFFER_SIZE] {
     if is_game_over {
         buffer[0] = 1;
     } else {
@@ -41,16 +47,49 @@ pub fn is_game_over(buffer: &mut [u8; 8], is_game_over: bool) -> [u8; 8] {
     buffer.clone()
 }
 
-pub fn read_game_over(buffer: &[u8; 8]) -> bool {
-    buffer[0] == 1
+pub fn read_game_over(buffer: &[u8; BUFFER_SIZE]) -> Result<bool, ProtocolError> {
+    match buffer[0] {
+        0 => Ok(false),
+        1 => Ok(true),
+        b => Err(ProtocolError::BadFlag(b)),
+    }
 }
 
 pub fn write_directions(
-    buffer: &mut [u8; 8],
+    buffer: &mut [u8; BUFFER_SIZE],
+    dir: Direction,
+    last_update_dir: Direction,
+    next_dir: Option<Direction>
+) -> [u8; BUFFER_SIZE] {
+    write_directions_at(buffer, 1, dir, last_update_dir, next_dir)
+}
+
+pub fn read_directions(buffer: &[u8; BUFFER_SIZE]) -> Result<(Direction, Direction, Option<Direction>), ProtocolError> {
+    read_directions_at(buffer, 1)
+}
+
+/// The server's state messages carry the directions of both snakes, the
+/// second one's in bytes 12 to 15.
+pub fn write_player2_directions(
+    buffer: &mut [u8; BUFFER_SIZE],
     dir: Direction,
     last_update_dir: Direction,
     next_dir: Option<Direction>
-) -> [u8; 8] {
+) -> [u8; BUFFER_SIZE] {
+    write_directions_at(buffer, 12, dir, last_update_dir, next_dir)
+}
+
+pub fn read_player2_directions(buffer: &[u8; BUFFER_SIZE]) -> Result<(Direction, Direction, Option<Direction>), ProtocolError> {
+    read_directions_at(buffer, 12)
+}
+
+fn write_directions_at(
+    buffer: &mut [u8; BUFFER_SIZE],
+    offset: usize,
+    dir: Direction,
+    last_update_dir: Direction,
+    next_dir: Option<Direction>
+) -> [u8; BUFFER_SIZE] {
     let dir_byte = dir.to_bytes();
     let last_update_dir_byte = last_update_dir.to_bytes();
     let next_dir_byte = match next_dir {
@@ -58,23 +97,210 @@ pub fn write_directions(
         None    => [4],
     };
 
-    buffer[1] = dir_byte[0];
-    buffer[2] = last_update_dir_byte[0];
-    buffer[3] = next_dir_byte[0];
+    buffer[offset] = dir_byte[0];
+    buffer[offset + 1] = last_update_dir_byte[0];
+    buffer[offset + 2] = next_dir_byte[0];
 
-    buffer.clone()
+    *buffer
 }
 
-pub fn read_directions(buffer: &[u8; 8]) -> (Direction, Direction, Option<Direction>) {
-    let dir = Direction::from_bytes(&[buffer[1]]);
-    let last_update_dir = Direction::from_bytes(&[buffer[2]]);
-    let next_dir = match &buffer[3] {
-        0 => Some(Direction::Up),
-        1 => Some(Direction::Down),
-        2 => Some(Direction::Left),
-        3 => Some(Direction::Right),
-        _ => None,
+fn read_directions_at(
+    buffer: &[u8; BUFFER_SIZE],
+    offset: usize
+) -> Result<(Direction, Direction, Option<Direction>), ProtocolError> {
+    let dir = Direction::from_bytes(&[buffer[offset]])?;
+    let last_update_dir = Direction::from_bytes(&[buffer[offset + 1]])?;
+    let next_dir = match buffer[offset + 2] {
+        4 => None,
+        b => Some(Direction::from_bytes(&[b])?),
     };
 
-    (dir, last_update_dir, next_dir)
-}
\ No newline at end of file
+    Ok((dir, last_update_dir, next_dir))
+}
+
+/// A client's input carries up to `MAX_INTENTS` directions its player
+/// asked for, in the order they did, in bytes 1 to 3. The rest are 4.
+pub fn write_intents(buffer: &mut [u8; BUFFER_SIZE], intents: &[Direction]) -> [u8; BUFFER_SIZE] {
+    for i in 0..MAX_INTENTS {
+        buffer[1 + i] = match intents.get(i) {
+            Some(d) => d.to_bytes()[0],
+            None    => 4,
+        };
+    }
+
+    *buffer
+}
+
+pub fn read_intents(buffer: &[u8; BUFFER_SIZE]) -> Result<Vec<Direction>, ProtocolError> {
+    let mut intents = Vec::new();
+    for &b in &buffer[1..1 + MAX_INTENTS] {
+        if b != 4 {
+            intents.push(Direction::from_bytes(&[b])?);
+        }
+    }
+
+    Ok(intents)
+}
+
+pub fn write_ready(buffer: &mut [u8; BUFFER_SIZE]) -> [u8; BUFFER_SIZE] {
+    buffer[0] = READY;
+
+    *buffer
+}
+
+pub fn read_ready(buffer: &[u8; BUFFER_SIZE]) -> bool {
+    buffer[0] == READY
+}
+
+/// A player's profile takes up bytes 1 to 14: their colour as red, green
+/// and blue in bytes 1 to 3, their skin in byte 4, and as much of their name
+/// as fits in UTF-8 in the rest, padded with zeros.
+pub fn write_profile(buffer: &mut [u8; BUFFER_SIZE], profile: &Profile) -> [u8; BUFFER_SIZE] {
+    buffer[1..4].copy_from_slice(&profile.colour.to_bytes());
+    buffer[4] = profile.skin.to_byte();
+
+    let mut end = profile.name.len().min(MAX_NAME_BYTES);
+    while !profile.name.is_char_boundary(end) {
+        end -= 1;
+    }
+    buffer[5..5 + end].copy_from_slice(&profile.name.as_bytes()[..end]);
+    for b in &mut buffer[5 + end..BUFFER_SIZE - 1] {
+        *b = 0;
+    }
+
+    *buffer
+}
+
+pub fn read_profile(buffer: &[u8; BUFFER_SIZE]) -> Profile {
+    let colour = Colour::from_bytes([buffer[1], buffer[2], buffer[3]]);
+    let skin = Skin::from_byte(buffer[4]);
+
+    let name = &buffer[5..BUFFER_SIZE - 1];
+    let end = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+    let name = clean_name(&String::from_utf8_lossy(&name[..end]));
+
+    Profile::new(name, colour, skin)
+}
+
+/// The start message carries the tick on which the snakes start moving in
+/// the first four bytes, and the initial food position in the last four.
+pub fn write_start_tick(buffer: &mut [u8; BUFFER_SIZE], tick: u32) -> [u8; BUFFER_SIZE] {
+    buffer[0..4].copy_from_slice(&tick.to_be_bytes());
+
+    *buffer
+}
+
+pub fn read_start_tick(buffer: &[u8; BUFFER_SIZE]) -> u32 {
+    let mut b = [0; 4];
+    b.copy_from_slice(&buffer[0..4]);
+
+    u32::from_be_bytes(b)
+}
+
+/// The start message also carries the seed of the server's RNG in bytes 8
+/// to 11, so that the client's RNG can keep up with it.
+pub fn write_seed(buffer: &mut [u8; BUFFER_SIZE], seed: u32) -> [u8; BUFFER_SIZE] {
+    buffer[8..12].copy_from_slice(&seed.to_be_bytes());
+
+    *buffer
+}
+
+pub fn read_seed(buffer: &[u8; BUFFER_SIZE]) -> u32 {
+    let mut b = [0; 4];
+    b.copy_from_slice(&buffer[8..12]);
+
+    u32::from_be_bytes(b)
+}
+
+/// Bytes 12 to 14 of the start message hold the colour the server gave the
+/// client's snake.
+pub fn write_colour(buffer: &mut [u8; BUFFER_SIZE], colour: Colour) -> [u8; BUFFER_SIZE] {
+    buffer[12..15].copy_from_slice(&colour.to_bytes());
+
+    *buffer
+}
+
+pub fn read_colour(buffer: &[u8; BUFFER_SIZE]) -> Colour {
+    Colour::from_bytes([buffer[12], buffer[13], buffer[14]])
+}
+
+/// Every message during the match says which tick it belongs to, in bytes
+/// 8 to 11.
+pub fn write_tick(buffer: &mut [u8; BUFFER_SIZE], tick: u32) -> [u8; BUFFER_SIZE] {
+    buffer[8..12].copy_from_slice(&tick.to_be_bytes());
+
+    *buffer
+}
+
+pub fn read_tick(buffer: &[u8; BUFFER_SIZE]) -> u32 {
+    let mut b = [0; 4];
+    b.copy_from_slice(&buffer[8..12]);
+
+    u32::from_be_bytes(b)
+}
+
+/// A checksum message carries the hash in bytes 0 to 7
+pub fn write_checksum(buffer: &mut [u8; BUFFER_SIZE], checksum: u64) -> [u8; BUFFER_SIZE] {
+    buffer[0..8].copy_from_slice(&checksum.to_be_bytes());
+
+    *buffer
+}
+
+pub fn read_checksum(buffer: &[u8; BUFFER_SIZE]) -> u64 {
+    let mut b = [0; 8];
+    b.copy_from_slice(&buffer[0..8]);
+
+    u64::from_be_bytes(b)
+}
+
+/// The last byte says what kind of message this is. Messages that belong to
+/// the match leave it at zero.
+pub fn write_kind(buffer: &mut [u8; BUFFER_SIZE], kind: u8) -> [u8; BUFFER_SIZE] {
+    buffer[BUFFER_SIZE - 1] = kind;
+
+    *buffer
+}
+
+pub fn read_kind(buffer: &[u8; BUFFER_SIZE]) -> u8 {
+    buffer[BUFFER_SIZE - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profiles_come_back_the_same() {
+        let profile = Profile::new("Ana".to_string(), Colour::rgb(10, 20, 30), Skin::Rounded);
+        let mut buffer = [0; BUFFER_SIZE];
+        buffer = write_profile(&mut buffer, &profile);
+
+        assert_eq!(read_profile(&buffer), profile);
+    }
+
+    #[test]
+    fn long_names_are_cut_between_characters() {
+        let profile = Profile::new("Ñandú Ñandú Ñandú".to_string(), Colour::rgb(0, 0, 0), Skin::Blocks);
+        let mut buffer = [0; BUFFER_SIZE];
+        buffer = write_profile(&mut buffer, &profile);
+
+        assert_eq!(read_profile(&buffer).name, "Ñandú Ñ");
+    }
+
+    #[test]
+    fn names_lose_control_characters_and_bad_utf8() {
+        let mut buffer = [0; BUFFER_SIZE];
+        buffer[5..10].copy_from_slice(b"A\x1bB\xffC");
+
+        assert_eq!(read_profile(&buffer).name, "AB\u{FFFD}C");
+    }
+
+    #[test]
+    fn a_missing_next_direction_is_four() {
+        let mut buffer = [0; BUFFER_SIZE];
+        buffer = write_directions(&mut buffer, Direction::Up, Direction::Left, None);
+
+        assert_eq!(buffer[3], 4);
+        assert_eq!(read_directions(&buffer), Ok((Direction::Up, Direction::Left, None)));
+    }
+}
@@ -0,0 +1,71 @@
+//! A record of everything that happened in one match, so that when someone
+//! reports a strange game we can see what led up to it. Every match gets a
+//! file of its own in the `logs` directory, with one JSON object per line,
+//! e.g.
+//!
+//! ```text
+//! {"address":"192.168.1.20:51234","event":"connect","tick":0,"transport":"tcp"}
+//! {"event":"start","lead":0,"mode":"server","players":["Karl","Ana"],"start_tick":24,"tick":0}
+//! {"dir":"up","event":"input","next_dir":null,"player":1,"tick":31}
+//! {"at":[12,7],"event":"food","player":2,"score":1,"tick":40}
+//! {"at":[5,5],"event":"collision","player":1,"score":3,"tick":77}
+//! {"event":"gameover","scores":[3,1],"tick":77,"winner":2}
+//! ```
+//!
+//! Networked matches start with where the other player connected from and
+//! over what. Whoever runs the match records the food and collisions. A
+//! client only records what it asked for and what it heard from the server.
+
+use log::warn;
+use serde_json::{json, Value};
+
+use std::fs::{self, File};
+use std::io::Write;
+
+use crate::config::app_dir;
+use crate::Mode;
+
+/// Where the event logs go, inside our directory
+const LOG_DIR: &str = "logs";
+
+pub struct EventLog {
+    /// The file we write to, or `None` if we couldn't make one
+    file: Option<File>,
+}
+
+impl EventLog {
+    /// Start the log for a new match. If it can't be written, the match goes
+    /// on without one.
+    pub fn create(mode: Mode) -> Self {
+        let mode = mode.name();
+        let dir = app_dir().join(LOG_DIR);
+        let name = format!("match-{}-{}.jsonl", chrono::Local::now().format("%Y%m%d-%H%M%S"), mode);
+
+        let file = fs::create_dir_all(&dir).and_then(|_| File::create(dir.join(&name)));
+        match file {
+            Ok(file) => EventLog { file: Some(file) },
+            Err(e) => {
+                warn!("Could not start the event log {}: {}", name, e);
+                EventLog { file: None }
+            }
+        }
+    }
+
+    /// Write down that `event` happened on `tick`, along with the `details`,
+    /// which is a JSON object
+    pub fn record(&mut self, tick: u128, event: &str, details: Value) {
+        let file = match self.file.as_mut() {
+            Some(file) => file,
+            None => return,
+        };
+
+        let mut line = json!({ "tick": tick as u64, "event": event });
+        if let (Some(line), Value::Object(details)) = (line.as_object_mut(), details) {
+            line.extend(details);
+        }
+        if let Err(e) = writeln!(file, "{}", line) {
+            warn!("Could not write to the event log: {}", e);
+            self.file = None;
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use oorandom::Rand32;
+use serde_json::{json, Value};
+
+use std::io;
+use std::net::TcpStream;
+use std::process::{self, Child, Stdio};
+
+use super::json::direction_name;
+use super::{Bot, Pilot, Player, RemoteBot, World};
+use crate::Difficulty;
+
+/// Matches where nobody makes a mistake have to end at some point. If both
+/// snakes are still alive after this many ticks, the longer one wins.
+const MAX_TICKS: u64 = 5000;
+
+/// Someone taking part in a headless match
+#[derive(Clone, Debug)]
+pub enum Contestant {
+    /// One of our own bots
+    Builtin(Difficulty),
+    /// A program we start, which speaks the bot API on its stdin and stdout
+    Command(Vec<String>),
+    /// A bot waiting for us to connect at an address, which speaks the bot
+    /// API once we do
+    Endpoint(String),
+}
+
+impl Contestant {
+    /// Read a contestant from the command line. This is one of
+    /// `builtin:easy`, `builtin:medium`, `builtin:hard`, `tcp:HOST:PORT`,
+    /// or else the command line of a bot program.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        if let Some(difficulty) = spec.strip_prefix("builtin:") {
+            match difficulty {
+                "easy"   => Ok(Contestant::Builtin(Difficulty::Easy)),
+                "medium" => Ok(Contestant::Builtin(Difficulty::Medium)),
+                "hard"   => Ok(Contestant::Builtin(Difficulty::Hard)),
+                _        => Err(format!("unknown bot difficulty: {}", difficulty)),
+            }
+        } else if let Some(address) = spec.strip_prefix("tcp:") {
+            Ok(Contestant::Endpoint(address.to_string()))
+        } else {
+            let args: Vec<String> = spec.split_whitespace().map(String::from).collect();
+            if args.is_empty() {
+                return Err("empty bot command".to_string());
+            }
+            Ok(Contestant::Command(args))
+        }
+    }
+
+    /// Get the contestant ready to play. Bot programs we started are
+    /// returned too, so that we can stop them after the match.
+    fn pilot(&self) -> io::Result<(Box<dyn Pilot>, Option<Child>)> {
+        match self {
+            Contestant::Builtin(difficulty) => Ok((Box::new(Bot::new(*difficulty)), None)),
+            Contestant::Command(args) => {
+                let mut child = process::Command::new(&args[0])
+                    .args(&args[1..])
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .spawn()?;
+                let stdin = child.stdin.take().expect("stdin is piped");
+                let stdout = child.stdout.take().expect("stdout is piped");
+
+                match RemoteBot::new(stdout, stdin) {
+                    Ok(bot) => Ok((Box::new(bot), Some(child))),
+                    Err(e) => {
+                        let _ = child.kill();
+                        Err(e)
+                    }
+                }
+            }
+            Contestant::Endpoint(address) => {
+                let bot = RemoteBot::over_tcp(TcpStream::connect(address)?)?;
+                Ok((Box::new(bot), None))
+            }
+        }
+    }
+}
+
+/// How a headless match went
+#[derive(Clone, Debug)]
+pub struct MatchRecord {
+    pub seed: u64,
+    pub ticks: u64,
+    /// How much food the first and second snake ate
+    pub scores: [u32; 2],
+    /// The seat of whoever won, if anyone did
+    pub winner: Option<usize>,
+    /// Which way each snake went and where the food was after every tick.
+    /// Together with the seed, this is enough to replay the whole match.
+    pub replay: Vec<Value>,
+}
+
+/// Play a match between two contestants without opening a window. The same
+/// seed always gives the same food positions, so two bots that always make
+/// the same moves always play the same match.
+pub fn play_match(contestants: [&Contestant; 2], seed: u64) -> io::Result<MatchRecord> {
+    let (pilot1, child1) = contestants[0].pilot()?;
+    let (pilot2, child2) = match contestants[1].pilot() {
+        Ok(seat) => seat,
+        Err(e) => {
+            if let Some(mut child) = child1 {
+                let _ = child.kill();
+            }
+            return Err(e);
+        }
+    };
+
+    let mut pilots = [Some(pilot1), Some(pilot2)];
+    for (pilot, player) in pilots.iter_mut().zip([Player::One, Player::Two].iter()) {
+        if let Some(pilot) = pilot {
+            pilot.start(*player);
+        }
+    }
+
+    let mut world = World::with_rng(Rand32::new(seed), false);
+    let mut replay = Vec::new();
+    let mut ticks = 0;
+
+    while !world.gameover && ticks < MAX_TICKS {
+        world.run_pilots(&mut pilots, ticks as u128);
+        world.step();
+        ticks += 1;
+
+        replay.push(json!({
+            "dirs": [direction_name(world.player1.dir), direction_name(world.player2.dir)],
+            "food": [world.food.pos.x, world.food.pos.y],
+        }));
+    }
+    world.end_pilots(&mut pilots);
+
+    for child in [child1, child2].iter_mut().flatten() {
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+
+    let scores = [world.player1.score(), world.player2.score()];
+    let winner = match world.won(Player::One) {
+        Some(true)  => Some(0),
+        Some(false) => Some(1),
+        // Nobody made a mistake, so the longer snake wins
+        None if scores[0] > scores[1] => Some(0),
+        None if scores[1] > scores[0] => Some(1),
+        None => None,
+    };
+
+    Ok(MatchRecord { seed, ticks, scores, winner, replay })
+}
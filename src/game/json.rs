@@ -0,0 +1,30 @@
+//! How directions and positions are written in JSON, which is the same in
+//! the bot API, in snapshots and in the event log.
+
+use serde_json::{json, Value};
+
+use super::{Direction, GridPosition};
+
+pub fn direction_name(dir: Direction) -> &'static str {
+    match dir {
+        Direction::Up    => "up",
+        Direction::Down  => "down",
+        Direction::Left  => "left",
+        Direction::Right => "right",
+    }
+}
+
+pub fn direction_from_name(name: &str) -> Option<Direction> {
+    match name {
+        "up"    => Some(Direction::Up),
+        "down"  => Some(Direction::Down),
+        "left"  => Some(Direction::Left),
+        "right" => Some(Direction::Right),
+        _       => None,
+    }
+}
+
+/// A position as it's written in JSON, as `[x, y]`
+pub fn position_to_json(pos: GridPosition) -> Value {
+    json!([pos.x, pos.y])
+}
@@ -0,0 +1,473 @@
+//! The connection between the server and the client. However it works
+//! underneath, it carries the same fixed-size messages both ways.
+//!
+//! Over TCP, every message arrives, in order, but a single lost packet holds
+//! up everything behind it until it's sent again. Over UDP, nothing waits:
+//! every datagram carries the newest message along with the few before it,
+//! so a lost datagram is usually made up for by the next one, and anything
+//! older than what we already have is dropped.
+//!
+//! The server also takes WebSocket connections on its TCP port, for clients
+//! that would rather not frame the messages themselves, like a browser. Every
+//! binary WebSocket message carries exactly one of our messages, laid out as
+//! described in `messages`. The same goes for spectators, see `spectate`.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tungstenite::handshake::server::{Callback, ErrorResponse, Request, Response};
+use tungstenite::{Message, WebSocket};
+
+use super::messages::ProtocolError;
+use super::BUFFER_SIZE;
+use crate::config::Config;
+use crate::netsim;
+
+/// How many messages every datagram carries: the newest one, and the ones
+/// sent right before it
+const REDUNDANCY: usize = 3;
+/// How long a WebSocket reader holds on to the connection before it lets a
+/// writer have a go
+const WEBSOCKET_POLL: Duration = Duration::from_millis(10);
+/// How long a TCP reader waits between looking for the rest of a message
+/// that only partly arrived
+const PARTIAL_POLL: Duration = Duration::from_millis(1);
+/// How long the client keeps knocking before it gives up on the server
+const SETUP_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often it knocks
+const SETUP_RETRY: Duration = Duration::from_millis(250);
+/// If we hear nothing for this long, the other player is gone
+const PEER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/* The first byte of every datagram says what it is */
+const HELLO: u8 = 1;
+const WELCOME: u8 = 2;
+const DATA: u8 = 3;
+/// A data datagram is its type, the number of messages in it, and then
+/// every message after its sequence number
+const MAX_DATAGRAM: usize = 2 + REDUNDANCY * (4 + BUFFER_SIZE);
+
+/// How the server and the client talk. Both of them have to use the same one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transport {
+    Tcp,
+    Udp,
+}
+
+impl Transport {
+    /// The transport the config file asks for with `net.transport`, which
+    /// is TCP unless it says `udp`.
+    pub fn from_config(config: &Config) -> Self {
+        match config.get("net.transport") {
+            Some(transport) if transport.eq_ignore_ascii_case("udp") => Transport::Udp,
+            _ => Transport::Tcp,
+        }
+    }
+}
+
+/// A connection to the other player
+pub trait Link: Send {
+    /// Send a message. Over UDP, this doesn't mean that it arrives.
+    fn send(&mut self, message: &[u8; BUFFER_SIZE]) -> io::Result<()>;
+
+    /// Wait for the next message for up to `timeout`, returning `None` if
+    /// none came in by then.
+    fn receive_within(&mut self, timeout: Duration) -> io::Result<Option<[u8; BUFFER_SIZE]>>;
+
+    /// Wait for the next message for as long as the link lets us, which
+    /// for UDP isn't forever.
+    fn receive(&mut self) -> io::Result<[u8; BUFFER_SIZE]>;
+
+    /// Another handle on the same connection, so we can read on one thread
+    /// while we write on another
+    fn try_clone(&self) -> io::Result<Box<dyn Link>>;
+
+    /// Hang up, so that the other side knows we're done with them
+    fn close(&mut self) {}
+
+    /// Where the other player is, if we can tell
+    fn peer_addr(&self) -> Option<SocketAddr>;
+
+    /// What the link runs over, for the event log
+    fn transport(&self) -> &'static str;
+}
+
+impl Link for TcpStream {
+    fn send(&mut self, message: &[u8; BUFFER_SIZE]) -> io::Result<()> {
+        self.write_all(message)
+    }
+
+    fn receive_within(&mut self, timeout: Duration) -> io::Result<Option<[u8; BUFFER_SIZE]>> {
+        // We only take a message off the stream once all of it is there, so
+        // that running out of time halfway through one doesn't lose our
+        // place in the stream
+        let deadline = Instant::now() + timeout;
+        let mut buffer = [0; BUFFER_SIZE];
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left == Duration::from_secs(0) {
+                return Ok(None);
+            }
+            self.set_read_timeout(Some(left))?;
+            let peeked = self.peek(&mut buffer);
+            self.set_read_timeout(None)?;
+
+            match peeked {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) if n == BUFFER_SIZE => return Link::receive(self).map(Some),
+                // The rest of the message is on its way
+                Ok(_) => thread::sleep(PARTIAL_POLL),
+                Err(e) if is_timeout(&e) => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn receive(&mut self) -> io::Result<[u8; BUFFER_SIZE]> {
+        let mut buffer = [0; BUFFER_SIZE];
+        self.read_exact(&mut buffer)?;
+        Ok(buffer)
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn Link>> {
+        Ok(Box::new(TcpStream::try_clone(self)?))
+    }
+
+    fn close(&mut self) {
+        let _ = self.shutdown(Shutdown::Both);
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        TcpStream::peer_addr(self).ok()
+    }
+
+    fn transport(&self) -> &'static str {
+        "tcp"
+    }
+}
+
+/// What both ends of a `UdpLink` share
+#[derive(Default)]
+struct UdpState {
+    /// The sequence number of the next message we send
+    next_sequence: u32,
+    /// The last few messages we sent, which go out again with the next one
+    sent: VecDeque<(u32, [u8; BUFFER_SIZE])>,
+    /// The sequence number of the newest message we received
+    received: Option<u32>,
+    /// Messages that came in together and haven't been read yet
+    pending: VecDeque<[u8; BUFFER_SIZE]>,
+}
+
+/// A connection to the other player over UDP
+pub struct UdpLink {
+    socket: UdpSocket,
+    state: Arc<Mutex<UdpState>>,
+    /// The simulated network we talk through, if any, which keeps going
+    /// for as long as we're around
+    simulation: Option<Arc<netsim::Simulation>>,
+}
+
+impl UdpLink {
+    /// Wait on `address` until a client says hello, and talk only to that
+    /// client from then on. We stop waiting as soon as `stop` says so.
+    pub fn accept(address: &str, stop: impl Fn() -> bool) -> io::Result<Self> {
+        let socket = UdpSocket::bind(address)?;
+        socket.set_read_timeout(Some(SETUP_RETRY))?;
+        let mut datagram = [0; MAX_DATAGRAM];
+
+        while !stop() {
+            match socket.recv_from(&mut datagram) {
+                Ok((read, peer)) if read >= 1 && datagram[0] == HELLO => {
+                    socket.connect(peer)?;
+                    socket.send(&[WELCOME])?;
+                    return Self::new(socket);
+                }
+                Ok(_) => (),
+                Err(e) if is_timeout(&e) => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        Err(io::Error::new(io::ErrorKind::Interrupted, "stopped waiting for a client"))
+    }
+
+    /// Say hello to the server at `address` until it answers.
+    pub fn connect(address: &str) -> io::Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(address)?;
+        socket.set_read_timeout(Some(SETUP_RETRY))?;
+
+        let started = Instant::now();
+        let mut datagram = [0; MAX_DATAGRAM];
+        while started.elapsed() < SETUP_TIMEOUT {
+            socket.send(&[HELLO])?;
+            match socket.recv(&mut datagram) {
+                Ok(read) if read >= 1 && datagram[0] == WELCOME => return Self::new(socket),
+                Ok(_) => (),
+                Err(e) if is_timeout(&e) => (),
+                // Nobody listening on that port
+                Err(e) => return Err(e),
+            }
+        }
+
+        Err(io::Error::new(io::ErrorKind::TimedOut, "the server didn't answer"))
+    }
+
+    fn new(socket: UdpSocket) -> io::Result<Self> {
+        socket.set_read_timeout(Some(PEER_TIMEOUT))?;
+        Ok(UdpLink {
+            socket,
+            state: Arc::new(Mutex::new(UdpState::default())),
+            simulation: None,
+        })
+    }
+
+    /// Talk through the simulated network from now on
+    pub fn simulated(self, settings: netsim::Settings) -> io::Result<Self> {
+        let (socket, simulation) = netsim::wrap_udp(self.socket, settings)?;
+        let mut link = Self::new(socket)?;
+        link.simulation = Some(Arc::new(simulation));
+        Ok(link)
+    }
+
+    /// Take the messages we haven't seen yet out of a datagram. One that
+    /// isn't laid out the way we send them means the other player isn't
+    /// playing along.
+    fn unpack(&self, datagram: &[u8]) -> io::Result<()> {
+        match datagram.first() {
+            Some(&DATA) => (),
+            // The client didn't get our welcome, and is still saying hello
+            Some(&HELLO) => {
+                self.socket.send(&[WELCOME])?;
+                return Ok(());
+            }
+            // We said hello more than once, so we got welcomed more than once
+            Some(&WELCOME) => return Ok(()),
+            Some(&kind) => return Err(ProtocolError::BadKind(kind).into()),
+            None => return Err(ProtocolError::BadLength(0).into()),
+        }
+
+        let count = datagram.get(1).copied().unwrap_or(0) as usize;
+        if count == 0 || count > REDUNDANCY || datagram.len() != 2 + count * (4 + BUFFER_SIZE) {
+            return Err(ProtocolError::BadLength(datagram.len()).into());
+        }
+        let mut state = self.state.lock().unwrap();
+        for chunk in datagram[2..].chunks_exact(4 + BUFFER_SIZE) {
+            let mut sequence = [0; 4];
+            sequence.copy_from_slice(&chunk[0..4]);
+            let sequence = u32::from_be_bytes(sequence);
+
+            // Anything we already have, or that is older than it, is stale
+            if state.received.is_some_and(|received| sequence <= received) {
+                continue;
+            }
+            let mut message = [0; BUFFER_SIZE];
+            message.copy_from_slice(&chunk[4..]);
+            state.pending.push_back(message);
+            state.received = Some(sequence);
+        }
+
+        Ok(())
+    }
+
+    fn receive_until(&mut self, deadline: Instant) -> io::Result<Option<[u8; BUFFER_SIZE]>> {
+        // One byte more than we ever send, so that we notice a datagram
+        // that's too long instead of reading only the start of it
+        let mut datagram = [0; MAX_DATAGRAM + 1];
+        loop {
+            if let Some(message) = self.state.lock().unwrap().pending.pop_front() {
+                return Ok(Some(message));
+            }
+
+            let left = match deadline.checked_duration_since(Instant::now()) {
+                Some(left) if left > Duration::from_millis(0) => left,
+                _ => return Ok(None),
+            };
+            self.socket.set_read_timeout(Some(left))?;
+            match self.socket.recv(&mut datagram) {
+                Ok(read) => {
+                    self.unpack(&datagram[..read])?;
+                }
+                Err(e) if is_timeout(&e) => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Link for UdpLink {
+    fn send(&mut self, message: &[u8; BUFFER_SIZE]) -> io::Result<()> {
+        let mut datagram = vec![DATA, 0];
+        {
+            let mut state = self.state.lock().unwrap();
+            let sequence = state.next_sequence;
+            state.next_sequence = state.next_sequence.wrapping_add(1);
+            state.sent.push_back((sequence, *message));
+            while state.sent.len() > REDUNDANCY {
+                state.sent.pop_front();
+            }
+
+            datagram[1] = state.sent.len() as u8;
+            for (sequence, message) in state.sent.iter() {
+                datagram.extend_from_slice(&sequence.to_be_bytes());
+                datagram.extend_from_slice(message);
+            }
+        }
+
+        self.socket.send(&datagram)?;
+        Ok(())
+    }
+
+    fn receive_within(&mut self, timeout: Duration) -> io::Result<Option<[u8; BUFFER_SIZE]>> {
+        self.receive_until(Instant::now() + timeout)
+    }
+
+    fn receive(&mut self) -> io::Result<[u8; BUFFER_SIZE]> {
+        match self.receive_until(Instant::now() + PEER_TIMEOUT)? {
+            Some(message) => Ok(message),
+            None => Err(io::Error::new(io::ErrorKind::TimedOut, "the other player went quiet")),
+        }
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn Link>> {
+        Ok(Box::new(UdpLink {
+            socket: self.socket.try_clone()?,
+            state: Arc::clone(&self.state),
+            simulation: self.simulation.clone(),
+        }))
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.socket.peer_addr().ok()
+    }
+
+    fn transport(&self) -> &'static str {
+        "udp"
+    }
+}
+
+/// A client that connected over WebSocket. Reading and writing share the one
+/// connection, so a reader only ever waits on it for a moment at a time.
+pub struct WebSocketLink {
+    socket: Arc<Mutex<WebSocket<TcpStream>>>,
+}
+
+impl WebSocketLink {
+    /// Do the WebSocket handshake with a client that just connected, and
+    /// tell which path it asked for
+    pub fn accept(stream: TcpStream) -> io::Result<(Self, String)> {
+        let mut path = String::new();
+        let socket = tungstenite::accept_hdr(stream, AskedFor(&mut path))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        socket.get_ref().set_read_timeout(Some(WEBSOCKET_POLL))?;
+
+        let link = WebSocketLink {
+            socket: Arc::new(Mutex::new(socket)),
+        };
+        Ok((link, path))
+    }
+
+    /// Give up on a send that takes longer than `timeout`
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.lock().unwrap().get_ref().set_write_timeout(timeout)
+    }
+
+    fn receive_until(&mut self, deadline: Option<Instant>) -> io::Result<Option<[u8; BUFFER_SIZE]>> {
+        loop {
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Ok(None);
+            }
+
+            let read = self.socket.lock().unwrap().read();
+            match read {
+                Ok(Message::Binary(data)) if data.len() == BUFFER_SIZE => {
+                    let mut message = [0; BUFFER_SIZE];
+                    message.copy_from_slice(&data);
+                    return Ok(Some(message));
+                }
+                // Every binary message is exactly one of ours
+                Ok(Message::Binary(data)) => return Err(ProtocolError::BadLength(data.len()).into()),
+                Ok(Message::Close(_)) => {
+                    return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "the client hung up"));
+                }
+                // Pings are answered for us, and anything else isn't ours
+                Ok(_) => (),
+                Err(tungstenite::Error::Io(e)) if is_timeout(&e) => (),
+                Err(e) => return Err(websocket_error(e)),
+            }
+        }
+    }
+}
+
+/// Notes down the path a WebSocket client asked for in its handshake
+struct AskedFor<'a>(&'a mut String);
+
+impl Callback for AskedFor<'_> {
+    fn on_request(self, request: &Request, response: Response) -> Result<Response, ErrorResponse> {
+        *self.0 = request.uri().path().to_string();
+        Ok(response)
+    }
+}
+
+impl Link for WebSocketLink {
+    fn send(&mut self, message: &[u8; BUFFER_SIZE]) -> io::Result<()> {
+        self.socket
+            .lock()
+            .unwrap()
+            .send(Message::Binary(message.to_vec()))
+            .map_err(websocket_error)
+    }
+
+    fn receive_within(&mut self, timeout: Duration) -> io::Result<Option<[u8; BUFFER_SIZE]>> {
+        self.receive_until(Some(Instant::now() + timeout))
+    }
+
+    fn receive(&mut self) -> io::Result<[u8; BUFFER_SIZE]> {
+        match self.receive_until(None)? {
+            Some(message) => Ok(message),
+            None => unreachable!("there is no deadline to miss"),
+        }
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn Link>> {
+        Ok(Box::new(WebSocketLink {
+            socket: Arc::clone(&self.socket),
+        }))
+    }
+
+    fn close(&mut self) {
+        let mut socket = self.socket.lock().unwrap();
+        let _ = socket.close(None);
+        let _ = socket.flush();
+        let _ = socket.get_ref().shutdown(Shutdown::Both);
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.socket.lock().unwrap().get_ref().peer_addr().ok()
+    }
+
+    fn transport(&self) -> &'static str {
+        "websocket"
+    }
+}
+
+fn websocket_error(e: tungstenite::Error) -> io::Error {
+    match e {
+        tungstenite::Error::Io(e) => e,
+        tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => {
+            io::Error::new(io::ErrorKind::ConnectionAborted, "the client hung up")
+        }
+        e => io::Error::other(e.to_string()),
+    }
+}
+
+/// Whether a read failed only because it ran out of time, which shows up as
+/// a different error on different platforms
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
@@ -0,0 +1,448 @@
+//! The messages the server and the client send each other once the match is
+//! running. Neither of them waits for the other anymore: the server runs the
+//! match and sends the result of every tick, while the client sends which
+//! keys its player pressed and predicts its own snake until the server's
+//! answer comes in. Only the server ever decides where a snake goes, so a
+//! client can't turn its snake around on itself, however it was modified.
+//! Either of them can also send a ping at any time, which the other answers
+//! with a pong right away, so we can tell how slow the connection is.
+//!
+//! Every message is 16 bytes. Numbers are big-endian, and a direction is one
+//! byte: 0 up, 1 down, 2 left, 3 right, with 4 meaning none where that's
+//! allowed. The last byte says what kind of message it is.
+//!
+//! | bytes  | state from the server           | input from the client      |
+//! |--------|---------------------------------|----------------------------|
+//! | 0      | 1 if the game is over           | 0                          |
+//! | 1..4   | first snake's direction, last   | up to three directions the |
+//! |        | direction and next direction    | player asked for, in order |
+//! | 4..8   | food x and y, as i16            | 0                          |
+//! | 8..12  | tick, as u32                    | tick, as u32               |
+//! | 12..15 | second snake's three directions | 0                          |
+//! | 15     | 0                               | 0                          |
+//!
+//! A ping or pong has its kind, 1 or 2, in the last byte and its number in
+//! bytes 8 to 12. Kind 3 starts the handshake of a match with a passphrase,
+//! and always has 0xA5 in the first byte.
+//!
+//! Every `CHECKSUM_EVERY` ticks, the server follows its state with a kind 4
+//! message holding the hash of its whole snapshot in bytes 0 to 8 and the
+//! tick in bytes 8 to 12. If the client's own hash is different, it answers
+//! with a kind 5 message with the tick, and the server sends it its whole
+//! snapshot in kind 6 messages: each has its number in bytes 0 to 2, how many
+//! there are in bytes 2 to 4, and the next 11 bytes of the snapshot, which
+//! starts with its length as u32.
+//!
+//! Before the match starts, the client sends 0xAA in the first byte once its
+//! window is up, along with its player's colour in bytes 1 to 4, skin in
+//! byte 4 and name in bytes 5 to 15. The server answers with the tick the
+//! snakes start moving on in bytes 0 to 4, the food in bytes 4 to 8, the
+//! seed of its RNG in bytes 8 to 12 and the colour the client's snake gets
+//! in bytes 12 to 15, which is the one it asked for unless that looks too
+//! much like the server's. Right after that, the server sends a kind 7
+//! message with its own player's colour, skin and name, in the same bytes
+//! as the client's.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+use super::snapshot::Snapshot;
+use super::{concat, Direction, GridPosition, Link, Steering, BUFFER_SIZE};
+
+/// Something the other player sent that can't be right. We hang up on
+/// them rather than play on with it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProtocolError {
+    /// A direction that isn't one of the four
+    BadDirection(u8),
+    /// A position that isn't on the board
+    OffTheBoard(i16, i16),
+    /// A flag that should be 0 or 1
+    BadFlag(u8),
+    /// A kind of message we don't know, or don't expect now
+    BadKind(u8),
+    /// A tick nowhere near the one the match is on
+    BadTick(u128),
+    /// A snapshot that doesn't add up
+    BadSnapshot,
+    /// A message, or a datagram of them, that's this many bytes long
+    /// when it shouldn't be
+    BadLength(usize),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::BadDirection(b) => write!(f, "{} is not a direction", b),
+            ProtocolError::OffTheBoard(x, y) => write!(f, "({}, {}) is not on the board", x, y),
+            ProtocolError::BadFlag(b) => write!(f, "{} is neither true nor false", b),
+            ProtocolError::BadKind(b) => write!(f, "unexpected message of kind {}", b),
+            ProtocolError::BadTick(tick) => write!(f, "tick {} is out of place", tick),
+            ProtocolError::BadSnapshot => write!(f, "the snapshot doesn't add up"),
+            ProtocolError::BadLength(length) => write!(f, "{} bytes is the wrong length", length),
+        }
+    }
+}
+
+impl Error for ProtocolError {}
+
+impl From<ProtocolError> for io::Error {
+    fn from(e: ProtocolError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+const KIND_MATCH: u8 = 0;
+const KIND_PING: u8 = 1;
+const KIND_PONG: u8 = 2;
+/// The handshake of a match with a passphrase, see `secure`
+pub const KIND_AUTH: u8 = 3;
+const KIND_CHECKSUM: u8 = 4;
+const KIND_DESYNC: u8 = 5;
+const KIND_SNAPSHOT: u8 = 6;
+/// The server's player, before the match starts
+pub const KIND_PROFILE: u8 = 7;
+
+/// How many bytes of a snapshot each message carries
+const SNAPSHOT_PART_SIZE: usize = BUFFER_SIZE - 5;
+
+/// What a message is about
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    /// Part of the match, i.e. a `TickState` or an `Input`
+    Match,
+    /// A ping with its number, which wants a pong with the same number back
+    Ping(u32),
+    /// The answer to a ping
+    Pong(u32),
+    /// The hash of the server's snapshot after a tick
+    Checksum(u32, u64),
+    /// The client's hash for a tick was different from the server's
+    Desync(u32),
+    /// A part of the server's snapshot, see `SnapshotParts`
+    Snapshot,
+}
+
+impl Kind {
+    /// What a message during the match is about
+    pub fn of(buffer: &[u8; BUFFER_SIZE]) -> Result<Self, ProtocolError> {
+        match concat::read_kind(buffer) {
+            KIND_MATCH => Ok(Kind::Match),
+            KIND_PING  => Ok(Kind::Ping(concat::read_tick(buffer))),
+            KIND_PONG  => Ok(Kind::Pong(concat::read_tick(buffer))),
+            KIND_CHECKSUM => Ok(Kind::Checksum(concat::read_tick(buffer), concat::read_checksum(buffer))),
+            KIND_DESYNC   => Ok(Kind::Desync(concat::read_tick(buffer))),
+            KIND_SNAPSHOT => Ok(Kind::Snapshot),
+            kind       => Err(ProtocolError::BadKind(kind)),
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; BUFFER_SIZE] {
+        let mut buffer = [0; BUFFER_SIZE];
+        match self {
+            Kind::Match => concat::write_kind(&mut buffer, KIND_MATCH),
+            Kind::Ping(number) => {
+                buffer = concat::write_tick(&mut buffer, number);
+                concat::write_kind(&mut buffer, KIND_PING)
+            }
+            Kind::Pong(number) => {
+                buffer = concat::write_tick(&mut buffer, number);
+                concat::write_kind(&mut buffer, KIND_PONG)
+            }
+            Kind::Checksum(tick, checksum) => {
+                buffer = concat::write_checksum(&mut buffer, checksum);
+                buffer = concat::write_tick(&mut buffer, tick);
+                concat::write_kind(&mut buffer, KIND_CHECKSUM)
+            }
+            Kind::Desync(tick) => {
+                buffer = concat::write_tick(&mut buffer, tick);
+                concat::write_kind(&mut buffer, KIND_DESYNC)
+            }
+            // The parts carry more than their kind, see `snapshot_messages`
+            Kind::Snapshot => concat::write_kind(&mut buffer, KIND_SNAPSHOT),
+        }
+    }
+}
+
+/// Split a snapshot into the messages that carry it
+pub fn snapshot_messages(snapshot: &[u8]) -> Vec<[u8; BUFFER_SIZE]> {
+    let mut bytes = (snapshot.len() as u32).to_be_bytes().to_vec();
+    bytes.extend_from_slice(snapshot);
+    let count = bytes.len().div_ceil(SNAPSHOT_PART_SIZE);
+
+    bytes
+        .chunks(SNAPSHOT_PART_SIZE)
+        .enumerate()
+        .map(|(number, part)| {
+            let mut buffer = Kind::Snapshot.to_bytes();
+            buffer[0..2].copy_from_slice(&(number as u16).to_be_bytes());
+            buffer[2..4].copy_from_slice(&(count as u16).to_be_bytes());
+            buffer[4..4 + part.len()].copy_from_slice(part);
+            buffer
+        })
+        .collect()
+}
+
+/// The parts of a snapshot that came in so far
+#[derive(Default)]
+pub struct SnapshotParts {
+    parts: Vec<Option<[u8; SNAPSHOT_PART_SIZE]>>,
+}
+
+impl SnapshotParts {
+    /// Take in another part, and get the whole snapshot once they're all
+    /// here. Parts of a snapshot with a different number of them must be
+    /// from a newer one, so we start over.
+    pub fn add(&mut self, buffer: &[u8; BUFFER_SIZE]) -> Result<Option<Snapshot>, ProtocolError> {
+        let number = u16::from_be_bytes([buffer[0], buffer[1]]) as usize;
+        let count = u16::from_be_bytes([buffer[2], buffer[3]]) as usize;
+        if number >= count {
+            return Err(ProtocolError::BadSnapshot);
+        }
+        if self.parts.len() != count {
+            self.parts = vec![None; count];
+        }
+
+        let mut part = [0; SNAPSHOT_PART_SIZE];
+        part.copy_from_slice(&buffer[4..4 + SNAPSHOT_PART_SIZE]);
+        self.parts[number] = Some(part);
+        if self.parts.iter().any(Option::is_none) {
+            return Ok(None);
+        }
+
+        let bytes: Vec<u8> = self.parts.drain(..).flatten().flatten().collect();
+        let len = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+        match bytes.get(4..4 + len) {
+            Some(snapshot) => Snapshot::from_bytes(snapshot).map(Some),
+            None => Err(ProtocolError::BadSnapshot),
+        }
+    }
+}
+
+/// What the server sends after every tick: how both snakes were steered on
+/// that tick, and where the food is and whether the game is over after it.
+#[derive(Clone, Debug)]
+pub struct TickState {
+    pub tick: u128,
+    pub steering: [Steering; 2],
+    pub food: GridPosition,
+    pub gameover: bool,
+}
+
+impl TickState {
+    pub fn to_bytes(&self) -> [u8; BUFFER_SIZE] {
+        let mut buffer = [0; BUFFER_SIZE];
+        let [one, two] = self.steering;
+
+        buffer = concat::is_game_over(&mut buffer, self.gameover);
+        buffer = concat::write_directions(&mut buffer, one.dir, one.last_update_dir, one.next_dir);
+        buffer = concat::add_position(&mut buffer, &self.food.to_bytes());
+        buffer = concat::write_tick(&mut buffer, self.tick as u32);
+        concat::write_player2_directions(&mut buffer, two.dir, two.last_update_dir, two.next_dir)
+    }
+
+    pub fn from_bytes(buffer: &[u8; BUFFER_SIZE]) -> Result<Self, ProtocolError> {
+        let (dir, last_update_dir, next_dir) = concat::read_directions(buffer)?;
+        let one = Steering { dir, last_update_dir, next_dir };
+        let (dir, last_update_dir, next_dir) = concat::read_player2_directions(buffer)?;
+        let two = Steering { dir, last_update_dir, next_dir };
+
+        Ok(TickState {
+            tick: concat::read_tick(buffer) as u128,
+            steering: [one, two],
+            food: GridPosition::from_bytes(&concat::read_position(buffer))?,
+            gameover: concat::read_game_over(buffer)?,
+        })
+    }
+}
+
+/// What the client sends on every tick: the directions its player asked
+/// for on it, which the server steers the client's snake with the same way
+/// it would if they had been pressed on its own keyboard.
+#[derive(Clone, Debug)]
+pub struct Input {
+    pub tick: u128,
+    pub intents: Vec<Direction>,
+}
+
+impl Input {
+    pub fn to_bytes(&self) -> [u8; BUFFER_SIZE] {
+        let mut buffer = [0; BUFFER_SIZE];
+
+        buffer = concat::write_intents(&mut buffer, &self.intents);
+        concat::write_tick(&mut buffer, self.tick as u32)
+    }
+
+    pub fn from_bytes(buffer: &[u8; BUFFER_SIZE]) -> Result<Self, ProtocolError> {
+        Ok(Input {
+            tick: concat::read_tick(buffer) as u128,
+            intents: concat::read_intents(buffer)?,
+        })
+    }
+}
+
+/// Read the messages the other player sends on their own thread, so that
+/// the game never has to wait for them. The channel is closed once the
+/// other player hangs up, or right after something they sent that can't
+/// be right.
+pub fn spawn_reader(link: &dyn Link) -> io::Result<Receiver<Result<[u8; BUFFER_SIZE], ProtocolError>>> {
+    let mut link = link.try_clone()?;
+    let (sender, messages) = mpsc::channel();
+
+    thread::spawn(move || {
+        // We stop once either the other player hung up, or we don't care anymore
+        loop {
+            let received = match link.receive() {
+                Ok(buffer) => Ok(buffer),
+                Err(e) => match e.get_ref().and_then(|inner| inner.downcast_ref::<ProtocolError>()) {
+                    Some(&e) => Err(e),
+                    None => break,
+                },
+            };
+            let rejected = received.is_err();
+            if sender.send(received).is_err() || rejected {
+                break;
+            }
+        }
+    });
+
+    Ok(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::world::World;
+
+    use oorandom::Rand32;
+
+    fn tick_state() -> TickState {
+        TickState {
+            tick: 42,
+            steering: [
+                Steering { dir: Direction::Up, last_update_dir: Direction::Up, next_dir: Some(Direction::Left) },
+                Steering { dir: Direction::Right, last_update_dir: Direction::Down, next_dir: None },
+            ],
+            food: GridPosition::new(3, 7),
+            gameover: true,
+        }
+    }
+
+    /// A snapshot of a board that was just set up
+    fn snapshot() -> Snapshot {
+        let world = World::new(Rand32::new(7), GridPosition::new(3, 7), false);
+        Snapshot::new(&world, 120)
+    }
+
+    /// The snapshot part numbered `number` out of `count`
+    fn part(number: u16, count: u16) -> [u8; BUFFER_SIZE] {
+        let mut buffer = Kind::Snapshot.to_bytes();
+        buffer[0..2].copy_from_slice(&number.to_be_bytes());
+        buffer[2..4].copy_from_slice(&count.to_be_bytes());
+        buffer
+    }
+
+    #[test]
+    fn kinds_come_back_the_same() {
+        for kind in [Kind::Match, Kind::Ping(7), Kind::Pong(7), Kind::Checksum(120, 0xDEAD_BEEF), Kind::Desync(120)] {
+            assert_eq!(Kind::of(&kind.to_bytes()), Ok(kind));
+        }
+    }
+
+    #[test]
+    fn unknown_kinds_are_rejected() {
+        let mut buffer = [0; BUFFER_SIZE];
+        buffer[BUFFER_SIZE - 1] = 99;
+        assert_eq!(Kind::of(&buffer), Err(ProtocolError::BadKind(99)));
+    }
+
+    #[test]
+    fn tick_states_come_back_the_same() {
+        let state = tick_state();
+        let read = TickState::from_bytes(&state.to_bytes()).unwrap();
+
+        assert_eq!(read.tick, state.tick);
+        assert_eq!(read.steering, state.steering);
+        assert_eq!(read.food, state.food);
+        assert_eq!(read.gameover, state.gameover);
+    }
+
+    #[test]
+    fn tick_states_that_cant_be_right_are_rejected() {
+        let mut buffer = tick_state().to_bytes();
+        buffer[0] = 2;
+        assert_eq!(TickState::from_bytes(&buffer).err(), Some(ProtocolError::BadFlag(2)));
+
+        let mut buffer = tick_state().to_bytes();
+        buffer[13] = 5;
+        assert_eq!(TickState::from_bytes(&buffer).err(), Some(ProtocolError::BadDirection(5)));
+
+        let mut buffer = tick_state().to_bytes();
+        buffer[4..8].copy_from_slice(&GridPosition::new(-1, 7).to_bytes());
+        assert_eq!(TickState::from_bytes(&buffer).err(), Some(ProtocolError::OffTheBoard(-1, 7)));
+    }
+
+    #[test]
+    fn inputs_come_back_the_same() {
+        let input = Input { tick: 9, intents: vec![Direction::Down, Direction::Left] };
+        let read = Input::from_bytes(&input.to_bytes()).unwrap();
+
+        assert_eq!(read.tick, 9);
+        assert_eq!(read.intents, input.intents);
+    }
+
+    #[test]
+    fn inputs_with_a_direction_that_isnt_one_are_rejected() {
+        let mut buffer = Input { tick: 9, intents: vec![Direction::Down] }.to_bytes();
+        buffer[2] = 200;
+        assert_eq!(Input::from_bytes(&buffer).err(), Some(ProtocolError::BadDirection(200)));
+    }
+
+    #[test]
+    fn snapshot_parts_come_back_together_in_any_order() {
+        let bytes = snapshot().to_bytes();
+        let mut messages = snapshot_messages(&bytes);
+        messages.reverse();
+        let last = messages.pop().unwrap();
+
+        let mut parts = SnapshotParts::default();
+        for message in &messages {
+            assert!(parts.add(message).unwrap().is_none());
+        }
+        let read = parts.add(&last).unwrap().expect("that was the last part");
+        assert_eq!(read.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn snapshot_parts_numbered_past_the_count_are_rejected() {
+        let mut parts = SnapshotParts::default();
+        assert_eq!(parts.add(&part(0, 0)).err(), Some(ProtocolError::BadSnapshot));
+        assert_eq!(parts.add(&part(3, 3)).err(), Some(ProtocolError::BadSnapshot));
+    }
+
+    #[test]
+    fn snapshots_longer_than_their_parts_are_rejected() {
+        // A single part can't hold a snapshot this long
+        let mut buffer = part(0, 1);
+        buffer[4..8].copy_from_slice(&1000u32.to_be_bytes());
+        assert_eq!(SnapshotParts::default().add(&buffer).err(), Some(ProtocolError::BadSnapshot));
+    }
+
+    #[test]
+    fn snapshots_with_a_short_snake_are_rejected() {
+        // The first snake says it has a single segment
+        let mut bytes = snapshot().to_bytes();
+        bytes[30..32].copy_from_slice(&1u16.to_be_bytes());
+        bytes.truncate(36);
+
+        let mut parts = SnapshotParts::default();
+        let mut result = Ok(None);
+        for message in snapshot_messages(&bytes) {
+            result = parts.add(&message);
+        }
+        assert_eq!(result.err(), Some(ProtocolError::BadSnapshot));
+    }
+}
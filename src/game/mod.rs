@@ -1,22 +1,61 @@
 use getrandom;
 use ggez;
+use log::{error, info, warn};
 use oorandom::Rand32;
+use serde_json::json;
 
 use ggez::event::{KeyCode, KeyMods};
 use ggez::{event, graphics, Context, GameResult};
 
-use std::collections::LinkedList;
+use std::collections::{BTreeMap, LinkedList};
+use std::io;
 use std::time::{Duration, Instant};
 
-use std::thread;
-use std::net::{TcpListener, TcpStream, Shutdown};
-use std::io::{Read, Write};
+use std::sync::mpsc::{Receiver, TryRecvError};
 
 use std::boxed::Box;
 
-use super::Mode;
+use super::{Difficulty, Mode};
+use super::config::Config;
+use super::error::Error;
+use super::highscores::HighScores;
 
+mod bindings;
+mod bot;
+mod botapi;
 mod concat;
+mod events;
+pub mod headless;
+mod json;
+mod link;
+mod messages;
+mod netstats;
+mod predict;
+mod profile;
+mod savegame;
+pub mod secure;
+mod skin;
+mod snapshot;
+mod spectate;
+mod world;
+
+use bindings::KeyBindings;
+pub use bindings::Layout;
+use bot::Bot;
+use events::EventLog;
+use json::direction_name;
+pub use link::{Link, Transport, UdpLink, WebSocketLink};
+use messages::{Input, Kind, SnapshotParts, TickState};
+pub use messages::ProtocolError;
+use netstats::NetStats;
+use predict::{Prediction, MAX_LEAD_TICKS};
+use snapshot::Snapshot;
+pub use botapi::RemoteBot;
+pub use profile::{Colour, Profile, PALETTE};
+pub use savegame::SavedGame;
+pub use skin::Skin;
+pub use spectate::{Spectators, WATCH_PATH};
+use world::World;
 
 #[derive(Clone, Copy, PartialEq, Eq)]
 enum Player { One, Two, }
@@ -31,7 +70,7 @@ impl Player {
 }
 
 /* Set up some constants that will help us out later */
-const BUFFER_SIZE: usize = 8;
+const BUFFER_SIZE: usize = 16;
 // We choose to make a 30x20 game board
 const GRID_SIZE: (i16, i16) = (30, 20);
 // We define the pixel size of each tile
@@ -48,11 +87,71 @@ const UPDATES_PER_SECOND: f32 = 8.0;
 // And we get the milliseconds of delay that this update rate
 // corresponds to
 const MILLIS_PER_UPDATE: u64 = (1.0 / UPDATES_PER_SECOND * 1000.0) as u64;
+// How long the 3-2-1 countdown before the first move lasts
+const COUNTDOWN_SECONDS: u32 = 3;
+// which is the tick on which the snakes start moving
+const START_TICK: u32 = COUNTDOWN_SECONDS * UPDATES_PER_SECOND as u32;
+// In single-player, every piece of food eaten makes the game this much faster
+const SPEEDUP_MILLIS_PER_FOOD: u64 = 3;
+// until we reach the fastest speed we think is still playable
+const MIN_MILLIS_PER_UPDATE: u64 = 50;
+// How long the client waits for the start message before saying it's ready again
+const READY_RETRY: Duration = Duration::from_millis(250);
+// The latest start a server can ask for, which is a minute of counting down
+const MAX_START_TICK: u32 = 60 * UPDATES_PER_SECOND as u32;
+// How many ticks we play on one frame at most, when catching up after a stall
+const MAX_TICKS_PER_FRAME: u32 = 4;
+// How much longer or shorter the client makes a tick for every tick it is
+// off from where the server says it should be
+const NUDGE_MILLIS: i64 = 4;
+// up to this many ticks, so that the game never gets much faster or slower
+const MAX_NUDGES: i64 = 4;
+// How far ahead of the server the client's inputs can be
+const MAX_INPUT_LEAD: u128 = 2 * MAX_LEAD_TICKS;
+// How often the server sends the hash of its whole board, which is every
+// five seconds
+const CHECKSUM_EVERY: u128 = 5 * UPDATES_PER_SECOND as u128;
+// The most key presses one input carries. A snake only remembers one turn
+// ahead anyway, so a fourth press on the same tick replaces the third.
+const MAX_INTENTS: usize = 3;
+
+/// Open the game window and play until it is closed. Networked games pass
+/// the link to the other player, while local games pass `None`. We give up
+/// waiting for the other player once `stop` says so, and anything that goes
+/// wrong before the match starts is an `Error::Handshake`.
+pub fn start_game(
+    link: Option<Box<dyn Link>>,
+    mode: Mode,
+    profile: Profile,
+    bot: Option<Difficulty>,
+    remote_bot: Option<RemoteBot>,
+    stop: &dyn Fn() -> bool,
+    spectators: Spectators,
+) -> Result<(), Error> {
+    play(mode, |bindings| {
+        let mut state = GameState::new(mode, link, bindings, profile, bot, remote_bot, stop)?;
+        spectators.introduce(&state.profiles);
+        state.spectators = spectators;
+        Ok(state)
+    })
+}
 
-pub fn start_game(stream: TcpStream, mode: Mode) -> GameResult {
+/// Pick up a game that was saved from the pause menu where it left off.
+pub fn resume_game(saved: SavedGame, profile: Profile, bot: Option<Difficulty>) -> Result<(), Error> {
+    play(saved.mode, |bindings| {
+        let mut state = GameState::new(saved.mode, None, bindings, profile, bot, None, &|| false)?;
+        state.restore(saved.snapshot);
+        Ok(state)
+    })
+}
+
+/// Open the window for a game of `mode`, and play the game that `new_state`
+/// sets up once it's open
+fn play(mode: Mode, new_state: impl FnOnce([KeyBindings; 2]) -> io::Result<GameState>) -> Result<(), Error> {
     let name = match mode {
         Mode::Server => "Snake server",
         Mode::Client => "Snake client",
+        Mode::Local | Mode::Single => "Snake",
     };
     // Here we use a ContextBuilder to setup metadata about our game.
     let (mut ctx, mut events_loop) = ggez::ContextBuilder::new(name, "Karl")
@@ -64,9 +163,21 @@ pub fn start_game(stream: TcpStream, mode: Mode) -> GameResult {
         // and finally we attempt to build the context and create the window. If it fails, we panic with
         // the message
         .build()?;
-        // Next we create a new instance of our GameState struct, which implements EventHandler
-        let mut state = GameState::new(mode, stream);
-        event::run(&mut ctx, &mut events_loop, &mut state)
+        // We look up which keys the players want to steer with
+        let config = Config::load();
+        let bindings = [
+            KeyBindings::from_config(&config, 1),
+            KeyBindings::from_config(&config, 2),
+        ];
+        // Next we create a new instance of our GameState struct, which implements EventHandler.
+        // This is where a networked game waits for the other player.
+        let mut state = new_state(bindings).map_err(Error::handshake)?;
+        event::run(&mut ctx, &mut events_loop, &mut state)?;
+        // The window is closed, but the match might have ended badly
+        match state.failure.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
 }
 
 // A struct that holds an entity's position on our game board
@@ -105,7 +216,8 @@ impl GridPosition {
         concat::concat_i16_i16(&x_bytes, &y_bytes)
     }
 
-    pub fn from_bytes(bytes: &[u8; 4]) -> GridPosition {
+    /// Read a position the other player sent, which had better be on the board
+    pub fn from_bytes(bytes: &[u8; 4]) -> Result<GridPosition, ProtocolError> {
         let mut x_bytes : [u8; 2] = [0; 2];
         let mut y_bytes : [u8; 2] = [0; 2];
 
@@ -120,7 +232,16 @@ impl GridPosition {
         let x = i16::from_be_bytes(x_bytes);
         let y = i16::from_be_bytes(y_bytes);
 
-        Self::new(x, y)
+        Self::on_board(x, y)
+    }
+
+    /// The position at `x` and `y`, if that's on the board at all
+    pub fn on_board(x: i16, y: i16) -> Result<GridPosition, ProtocolError> {
+        if (0..GRID_SIZE.0).contains(&x) && (0..GRID_SIZE.1).contains(&y) {
+            Ok(Self::new(x, y))
+        } else {
+            Err(ProtocolError::OffTheBoard(x, y))
+        }
     }
 
     /// As well as a helper function that will give us a random 
@@ -210,27 +331,13 @@ impl Direction {
         }
     }
 
-    pub fn from_bytes(bytes: &[u8; 1]) -> Direction {
+    pub fn from_bytes(bytes: &[u8; 1]) -> Result<Direction, ProtocolError> {
         match *bytes {
-            [0] => Direction::Up,
-            [1] => Direction::Down,
-            [2] => Direction::Left,
-            [3] => Direction::Right,
-             _  => panic!("Error"),
-        }
-    }
-
-    /// We also create a helper function that will let us convert between a
-    /// `ggez` KeyCode and the Direction that it represents. Of course,
-    /// not every keycde represents a direction, so we return `None` if this
-    /// is the case.
-    pub fn from_keycode(key: KeyCode) -> Option<Direction> {
-        match key {
-            KeyCode::Up    => Some(Direction::Up),
-            KeyCode::Down  => Some(Direction::Down),
-            KeyCode::Left  => Some(Direction::Left),
-            KeyCode::Right => Some(Direction::Right),
-            _              => None,
+            [0] => Ok(Direction::Up),
+            [1] => Ok(Direction::Down),
+            [2] => Ok(Direction::Left),
+            [3] => Ok(Direction::Right),
+            [b] => Err(ProtocolError::BadDirection(b)),
         }
     }
 }
@@ -251,6 +358,7 @@ impl Segment {
 
 /// This is again an abstraction over a GridPosition that represents a
 /// piece of food the snake can eat. It can draw itself.
+#[derive(Clone)]
 struct Food {
     pos: GridPosition,
 }
@@ -260,15 +368,6 @@ impl Food {
         Food { pos }
     }
 
-    pub fn to_bytes(&self) -> [u8; 4] {
-        self.pos.to_bytes()
-    }
-
-    pub fn from_bytes(bytes: &[u8; 4]) -> Self {
-        let grid_pos = GridPosition::from_bytes(bytes);
-        Self::new(grid_pos)
-    }
-
     /// We have a function that takes in `&mut ggez::Context` which we use with the
     /// helpers in `ggez::graphics` to do the drawing. We also return a 
     /// `ggez::GameResult` so that we can use the `?` operator to bubble up failure
@@ -308,8 +407,41 @@ enum Ate {
     Food,
 }
 
+/// Anything other than this machine's keyboard that steers a snake, like a
+/// bot. Every tick, before the snakes move, the pilot gets to look at the
+/// board and pick a direction, which is then applied just like a key press.
+trait Pilot {
+    /// Called once before the match starts, with the snake we steer.
+    fn start(&mut self, _player: Player) {}
+
+    /// Pick the direction `me` should go in next. `other` is the opponent's
+    /// snake, if there is one, and `tick` the number of the current update.
+    /// Returns `None` to leave the snake as it is.
+    fn next_direction(
+        &mut self,
+        me: &Snake,
+        other: Option<&Snake>,
+        food: &Food,
+        tick: u128,
+    ) -> Option<Direction>;
+
+    /// Called once the match is over, with whether we won it, or `None`
+    /// for a draw.
+    fn game_over(&mut self, _won: Option<bool>) {}
+}
+
+/// Everything about where a snake is heading. This is all the other player
+/// needs to know to move our snake the same way we do.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Steering {
+    dir: Direction,
+    last_update_dir: Direction,
+    next_dir: Option<Direction>,
+}
+
 /// Now we make a struct that contains all the information needed to describe the 
 /// state of the Snake itself.
+#[derive(Clone)]
 struct Snake {
     /// First we have the head of the snake, which is a single `Segment`.
     head: Segment,
@@ -332,30 +464,11 @@ struct Snake {
     /// `update`. This is needed so a user can press two directions (left then up)
     /// before one `update` has happened. It sort of queues up key press input
     next_dir: Option<Direction>,
-    /// The color of the snake's head
-    head_color: graphics::Color,
-    /// The color of the snake's body
-    body_color: graphics::Color,
 }
 
 impl Snake {
-    pub fn new(pos: GridPosition, player: Player) -> Self {
+    pub fn new(pos: GridPosition) -> Self {
         let mut body = LinkedList::new();
-        let body_color: graphics::Color;
-        let head_color: graphics::Color;
-        // Set the colors
-        match player {
-            Player::One => {
-                head_color = [0.3, 0.3, 0.0, 1.0].into();
-                body_color = [1.0, 0.5, 0.0, 1.0].into();
-            },
-            Player::Two => {
-                head_color = [0.2, 0.3, 0.4, 1.0].into();
-                body_color = [0.3, 0.7, 0.2, 1.0].into();
-            }
-        }
-
-
         // our snake will initially have a head and one body segment,
         // and will be moving to the right.
         body.push_back(Segment::new((pos.x - 1, pos.y).into()));
@@ -366,11 +479,15 @@ impl Snake {
             body: body,
             ate: None,
             next_dir: None,
-            head_color,
-            body_color,
         }
     }
 
+    /// The score is the number of pieces of food eaten, which is how much
+    /// the snake grew from the single body segment it started with.
+    fn score(&self) -> u32 {
+        (self.body.len() as u32).saturating_sub(1)
+    }
+
     /// A helper function that determines whether the snake eats a given
     /// piece of Food based on its current position.
     fn eats(&self, food: &Food) -> bool {
@@ -435,230 +552,999 @@ impl Snake {
         self.last_update_dir = self.dir;
     }
 
-    /// Here we have the Snake draw itself. This is very similar to how we saw
-    /// the food draw itself earlier
-    fn draw(&self, ctx: &mut Context) -> GameResult<()> {
-        // We first iterate through the body segments and draw them.
-        for seg in self.body.iter() {
-            // Again, we set the color (in this case an orangey color)
-            // and then draw the rect that we convert that segment's position into.
-            let rectangle = graphics::Mesh::new_rectangle(
-                ctx,
-                graphics::DrawMode::fill(),
-                seg.pos.into(),
-                self.body_color,
-                //[0.3, 0.3, 0.0, 1.0].into(),
-            )?;
-            graphics::draw(ctx, &rectangle, (ggez::mint::Point2 {x:0.0, y:0.0},))?;
-        }
-        // And then do the same for the head, instead making it fully red to
-        // distinguish it.
-        let rectangle = graphics::Mesh::new_rectangle(
-            ctx,
-            graphics::DrawMode::fill(),
-            self.head.pos.into(),
-            self.head_color,
-            //[1.0, 0.5, 0.0, 1.0].into(),
-        )?;
-        graphics::draw(ctx, &rectangle, (ggez::mint::Point2 {x: 0.0, y: 0.0 },))?;
+    /// Turn the snake towards `dir` when the player presses a direction key.
+    fn steer(&mut self, dir: Direction) {
+        // We check if the new direction has already been set,
+        // and make sure the new direction is different then `snake.dir`
+        if self.dir != self.last_update_dir && dir.inverse() != self.dir {
+            self.next_dir = Some(dir);
+        } else if dir.inverse() != self.last_update_dir {
+            // If no new direction has been set and the direction is not the inverse,
+            // of the last_update_dir, then set the snake's new direction to be
+            // the direction the user pressed.
+            self.dir = dir;
+        }
+    }
 
-        Ok(())
+    fn steering(&self) -> Steering {
+        Steering {
+            dir: self.dir,
+            last_update_dir: self.last_update_dir,
+            next_dir: self.next_dir,
+        }
+    }
+
+    /// Steer the snake exactly the way someone else did
+    fn set_steering(&mut self, steering: Steering) {
+        self.dir = steering.dir;
+        self.last_update_dir = steering.last_update_dir;
+        self.next_dir = steering.next_dir;
+    }
+
+    /// Where the head and then every body segment are
+    fn segments(&self) -> Vec<GridPosition> {
+        std::iter::once(self.head.pos)
+            .chain(self.body.iter().map(|seg| seg.pos))
+            .collect()
     }
+
+    /// Here we have the Snake draw itself the way its player wants it to
+    /// look. Every skin knows how to draw a snake from the cells it's on
+    /// and the way it's heading.
+    fn draw(&self, ctx: &mut Context, profile: &Profile) -> GameResult<()> {
+        let cells: Vec<(f32, f32)> = self
+            .segments()
+            .into_iter()
+            .map(|pos| (pos.x as f32, pos.y as f32))
+            .collect();
+
+        profile.skin.draw(ctx, &cells, self.dir, profile.colour)
+    }
+}
+
+/// Something the server sent the client during the match
+enum FromServer {
+    /// The result of a tick
+    State(TickState),
+    /// The hash of the server's whole board after a tick
+    Checksum(u128, u64),
+    /// The server's whole board, because ours drifted from it
+    Snapshot(Snapshot),
+}
+
+/// Write both sides' boards to disk when they didn't agree, so we can find
+/// out why later.
+fn dump_desync(ours: &Snapshot, theirs: &Snapshot) {
+    let name = format!("desync-{}", theirs.tick);
+    let dumped = ours
+        .dump(&format!("{}-client", name))
+        .and_then(|_| theirs.dump(&format!("{}-server", name)));
+    match dumped {
+        Ok(path) => info!("Dumped both boards next to {}", path.display()),
+        Err(e) => error!("Could not dump the boards: {}", e),
+    }
+}
+
+/// What waiting for the other player ends with when the launcher gave up on it
+fn cancelled() -> io::Error {
+    io::Error::new(io::ErrorKind::Interrupted, "stopped waiting for the other player")
 }
 
 /// Now we have the heart of our game, the GameState. This struct will implement
 /// ggez's `EventHandler` trait and will therefore drive everything else that happens
 /// in our game
 struct GameState {
-    /// We first need a snake
-    player1: Snake,
-    player2: Snake,
+    /// The board with the snakes and the food on it
+    world: World,
     mode: Mode,
-    /// A piece of food
-    food: Food,
-    /// Whether the game is over or not
-    gameover: bool,
-    /// Our RNG state
-    rng: Rand32,
     /// and we track the last time we updated so that we can limit 
     /// our update rate
     last_update: Instant,
-    /// TCP Stream
-    stream: TcpStream,
+    /// The connection to the other player, which local games don't have
+    link: Option<Box<dyn Link>>,
+    /// The messages the other player sent us, read on their own thread
+    incoming: Option<Receiver<Result<[u8; BUFFER_SIZE], ProtocolError>>>,
+    /// Where the client asked to go on ticks the server hasn't played yet
+    pending_inputs: BTreeMap<u128, Vec<Direction>>,
+    /// Where the client's player asked to go since the last tick
+    intents: Vec<Direction>,
+    /// What the client predicts from what the server confirmed
+    prediction: Option<Prediction>,
+    /// The parts of the server's snapshot that came in so far
+    snapshot_parts: SnapshotParts,
+    /// The last tick the server sent the client its snapshot on
+    resynced_at: Option<u128>,
+    /// How the connection to the other player is doing
+    stats: NetStats,
+    /// Whether we show those numbers on the screen
+    show_stats: bool,
+    /// Whether a local game is paused, with the pause menu up
+    paused: bool,
+    /// What ended the match early, if anything
+    failure: Option<Error>,
+    /// Everything that happened in this match
+    events: EventLog,
+    /// How the snakes were steered after the last tick, so we can tell
+    /// when someone turns
+    logged_steering: [Steering; 2],
     update_nbr: u128,
+    /// How many ticks the client runs ahead of the last state the server
+    /// sent, which is zero everywhere else
+    lead: u128,
+    /// The tick on which the snakes start moving. Every tick before it is
+    /// part of the countdown.
+    start_tick: u128,
+    /// The keys the players on this machine steer with. Networked games
+    /// only use the first set, while local games give one to each player.
+    bindings: [KeyBindings; 2],
+    /// Who plays the first and the second snake
+    profiles: [Profile; 2],
+    /// Whatever steers the first and second snake instead of the keyboard,
+    /// if anything
+    pilots: [Option<Box<dyn Pilot>>; 2],
+    /// Whoever is watching the match, on the server
+    spectators: Spectators,
 }
 
 impl GameState {
     /// Our new function will set up the initial state of our game.
-    pub fn new(mode: Mode, mut stream: TcpStream) -> Self {
-        // First we put our snake a quarter of the way accross our grid in the x axis.
-        // and half way down the y axis. This works well since we start out moving to the right
-        let mod_pos = GRID_SIZE.1 / 4;
-        let snake_pos_2 = (GRID_SIZE.0 / 4, mod_pos + GRID_SIZE.1 / 2).into();
-        let snake_pos_1 = (GRID_SIZE.0 / 4, mod_pos).into();
-        // and we seed ourRNG with the system RNG.
+    pub fn new(
+        mode: Mode,
+        mut link: Option<Box<dyn Link>>,
+        bindings: [KeyBindings; 2],
+        profile: Profile,
+        bot: Option<Difficulty>,
+        remote_bot: Option<RemoteBot>,
+        stop: &dyn Fn() -> bool,
+    ) -> io::Result<Self> {
+        // We seed our RNG with the system RNG.
         let mut seed: [u8; 8] = [0; 8];
-        getrandom::getrandom(&mut seed[..]).expect("Could not create RNG seed");
+        getrandom::getrandom(&mut seed[..]).map_err(|e| io::Error::other(e.to_string()))?;
 
         let food_pos;
         let mut rng = Rand32::new(u64::from_ne_bytes(seed));
 
+        let start_tick;
+        // The client runs this many ticks ahead of the server
+        let mut lead = 0;
+        // The client plays the second snake, and everyone else the first
+        let mut profiles = match mode {
+            Mode::Client => [Profile::default_for(Player::One), profile],
+            _ => [profile, Profile::default_for(Player::Two)],
+        };
+
         match mode {
             Mode::Server => {
+                let link = link.as_mut().expect("a networked game needs a link");
+                // Wait until the client tells us that its window is up,
+                // and who's playing there
+                let ready = loop {
+                    if stop() {
+                        return Err(cancelled());
+                    }
+                    match link.receive_within(READY_RETRY)? {
+                        Some(buffer) if concat::read_ready(&buffer) => break buffer,
+                        _ => (),
+                    }
+                };
+                profiles[1] = concat::read_profile(&ready);
+                // Both snakes can't look the same, and we were here first
+                profiles[1].colour = Colour::resolve(profiles[0].colour, profiles[1].colour);
+
+                // Both of us are ready, so we tell the client on which tick
+                // the snakes start moving along with the initial food position,
+                // and how to seed its RNG so that it stays the same as ours
                 let mut buffer = [0; BUFFER_SIZE];
+                let shared_seed = u32::from_ne_bytes([seed[0], seed[1], seed[2], seed[3]]);
+                rng = Rand32::new(shared_seed as u64);
                 food_pos = GridPosition::random(&mut rng, GRID_SIZE.0, GRID_SIZE.1);
-                // Send the initial food position to the client
+                start_tick = START_TICK;
+                buffer = concat::write_start_tick(&mut buffer, start_tick);
                 buffer = concat::add_position(&mut buffer, &food_pos.to_bytes());
-                let _ = stream.write(&buffer).unwrap();
+                buffer = concat::write_seed(&mut buffer, shared_seed);
+                buffer = concat::write_colour(&mut buffer, profiles[1].colour);
+                link.send(&buffer)?;
+                // and tell it who's playing here
+                let mut ours = [0; BUFFER_SIZE];
+                ours = concat::write_profile(&mut ours, &profiles[0]);
+                ours = concat::write_kind(&mut ours, messages::KIND_PROFILE);
+                link.send(&ours)?;
             }
             Mode::Client => {
-                /* Receive the initial food position */
-                let mut buffer = [0; BUFFER_SIZE];
-                let _ = stream.read_exact(&mut buffer).unwrap();
+                let link = link.as_mut().expect("a networked game needs a link");
+                // Our window is up, so we tell the server that we're ready.
+                // The message might get lost over UDP, so we say it again
+                // until the server answers.
+                let mut ready = [0; BUFFER_SIZE];
+                ready = concat::write_ready(&mut ready);
+                ready = concat::write_profile(&mut ready, &profiles[1]);
+                let (sent, buffer) = loop {
+                    if stop() {
+                        return Err(cancelled());
+                    }
+                    let sent = Instant::now();
+                    link.send(&ready)?;
+
+                    /* Receive the start tick and the initial food position */
+                    if let Some(buffer) = link.receive_within(READY_RETRY)? {
+                        break (sent, buffer);
+                    }
+                };
+                // Anything else than the start of the match means the server
+                // isn't playing along
+                if Kind::of(&buffer)? != Kind::Match {
+                    return Err(ProtocolError::BadKind(concat::read_kind(&buffer)).into());
+                }
+                // Our inputs take half the round trip to reach the server,
+                // so we run that many ticks ahead of it, plus one to be safe
+                let one_way = sent.elapsed().as_millis() / 2;
+                lead = (one_way / MILLIS_PER_UPDATE as u128 + 1).min(MAX_LEAD_TICKS);
+                start_tick = concat::read_start_tick(&buffer);
+                if start_tick > MAX_START_TICK {
+                    return Err(ProtocolError::BadTick(start_tick as u128).into());
+                }
                 let pos = concat::read_position(&buffer);
-                let gp = GridPosition::from_bytes(&pos);
+                let gp = GridPosition::from_bytes(&pos)?;
                 food_pos = gp;
+                // The server already picked the food with its RNG, so ours
+                // does the same to keep up
+                rng = Rand32::new(concat::read_seed(&buffer) as u64);
+                GridPosition::random(&mut rng, GRID_SIZE.0, GRID_SIZE.1);
+                // The server might have given us another colour than we
+                // asked for, and says who's playing there next
+                profiles[1].colour = concat::read_colour(&buffer);
+                let theirs = loop {
+                    if stop() {
+                        return Err(cancelled());
+                    }
+                    if let Some(buffer) = link.receive_within(READY_RETRY)? {
+                        break buffer;
+                    }
+                };
+                match concat::read_kind(&theirs) {
+                    messages::KIND_PROFILE => profiles[0] = concat::read_profile(&theirs),
+                    kind => return Err(ProtocolError::BadKind(kind).into()),
+                }
+            }
+            Mode::Local | Mode::Single => {
+                // Nobody to wait for, so we count down right away
+                food_pos = GridPosition::random(&mut rng, GRID_SIZE.0, GRID_SIZE.1);
+                start_tick = START_TICK;
             }
         }
 
-        GameState {
-            player1: Snake::new(snake_pos_1, Player::One),
-            player2: Snake::new(snake_pos_2, Player::Two),
+        let bot_name = bot.map(|difficulty| format!("Bot ({})", difficulty));
+        let bot = bot.map(|difficulty| Box::new(Bot::new(difficulty)) as Box<dyn Pilot>);
+        let mut pilots = match mode {
+            // A local bot takes the second player's seat, which is also
+            // how a server fills the seat nobody joined
+            Mode::Local  => [None, bot],
+            // A client or a lone player can let it play for them
+            Mode::Client => [None, bot],
+            Mode::Single => [bot, None],
+            // while a server's own seat is always its player's
+            Mode::Server => [None, None],
+        };
+        // The second player of a local game is whoever took its seat
+        if mode == Mode::Local {
+            if let Some(name) = bot_name {
+                profiles[1].name = name;
+            }
+            if let Some(remote_bot) = &remote_bot {
+                // The bot picked its own name, so it gets the same treatment
+                // as one that came over the network
+                profiles[1].name = profile::clean_name(remote_bot.name());
+            }
+            profiles[1].colour = Colour::resolve(profiles[0].colour, profiles[1].colour);
+        }
+        // A remote bot that connected over the bot API always plays the
+        // second snake of a local game
+        if let Some(remote_bot) = remote_bot {
+            pilots[1] = Some(Box::new(remote_bot.in_background()));
+        }
+        for (pilot, player) in pilots.iter_mut().zip([Player::One, Player::Two].iter()) {
+            if let Some(pilot) = pilot {
+                pilot.start(*player);
+            }
+        }
+
+        let world = World::new(rng, food_pos, mode == Mode::Single);
+        let incoming = link.as_deref().map(messages::spawn_reader).transpose()?;
+        let prediction = match mode {
+            Mode::Client => Some(Prediction::new(&world, start_tick as u128)),
+            _ => None,
+        };
+
+        let mut events = EventLog::create(mode);
+        if let Some(link) = link.as_deref() {
+            events.record(lead, "connect", json!({
+                "address": link.peer_addr().map(|address| address.to_string()),
+                "transport": link.transport(),
+            }));
+        }
+        events.record(lead, "start", json!({
+            "mode": mode.name(),
+            "players": [profiles[0].name, profiles[1].name],
+            "start_tick": start_tick,
+            "lead": lead as u64,
+        }));
+        let logged_steering = [world.player1.steering(), world.player2.steering()];
+
+        Ok(GameState {
+            world,
             mode,
-            food: Food::new(food_pos),
-            gameover: false,
-            rng,
             last_update: Instant::now(),
-            stream,
-            update_nbr: 0,
+            link,
+            incoming,
+            pending_inputs: BTreeMap::new(),
+            intents: Vec::new(),
+            prediction,
+            snapshot_parts: SnapshotParts::default(),
+            resynced_at: None,
+            // The server's states trail the client by its lead on the way
+            // out, and about as much again on the way back
+            stats: NetStats::new(lead * 2),
+            show_stats: false,
+            paused: false,
+            failure: None,
+            events,
+            logged_steering,
+            update_nbr: lead,
+            lead,
+            start_tick: start_tick as u128,
+            bindings,
+            profiles,
+            pilots,
+            spectators: Spectators::default(),
+        })
+    }
+
+    /// Send a message to the other player. If they're gone, the game is over.
+    fn send(&mut self, buffer: &[u8; BUFFER_SIZE]) {
+        let link = match self.link.as_deref_mut() {
+            Some(link) => link,
+            // We already hung up on them
+            None => return,
+        };
+        match link.send(buffer) {
+            Ok(()) => self.stats.sent(),
+            Err(e) => {
+                warn!("Lost the connection to the other player: {}", e);
+                self.events.record(self.update_nbr, "disconnected", json!({ "reason": e.to_string() }));
+                self.fail(Error::PeerGone(e.to_string()));
+            }
         }
     }
-}
 
-/// Now we implement EventHandler for GameState. This provides an interface 
-/// that ggez will call automatically when different events happen.
-impl event::EventHandler for GameState {
-    /// Update will happen on every frame before it is drawn. This is where
-    /// we update our game state to react to whatever is happening in the game
-    /// world.
-    fn update(&mut self, _ctx: &mut Context) -> GameResult {
-        // First we check to see if enough time has elapsed since our last update
-        // based on the update rate so we defined at the top
-        // if not, we do nothing and return early.
-        let mut buffer = [0; BUFFER_SIZE];
+    /// End the match because of `e`, which the launcher shows once the
+    /// window is closed. Only the first thing that went wrong counts.
+    fn fail(&mut self, e: Error) {
+        self.world.gameover = true;
+        if self.failure.is_none() {
+            self.failure = Some(e);
+        }
+    }
 
-        if !(Instant::now() - self.last_update >= Duration::from_millis(MILLIS_PER_UPDATE)) {
-            return Ok(());
+    /// Remember that the client's player wants to go `dir`, to tell the
+    /// server on the next tick.
+    fn intend(&mut self, dir: Direction) {
+        if self.intents.len() == MAX_INTENTS {
+            self.intents.pop();
+        }
+        self.intents.push(dir);
+    }
+
+    /// Hang up on the other player, because they sent something that can't
+    /// be right. The game is over.
+    fn reject(&mut self, e: ProtocolError) {
+        warn!("The other player sent bad data: {}", e);
+        self.events.record(self.update_nbr, "rejected", json!({ "reason": e.to_string() }));
+        self.fail(e.into());
+        if let Some(mut link) = self.link.take() {
+            link.close();
+        }
+        self.incoming = None;
+    }
+
+    /// Everything about the match the other player sent since we last
+    /// looked. Pings are answered on the way. If they hung up, the game is over.
+    fn receive(&mut self) -> Vec<(Kind, [u8; BUFFER_SIZE])> {
+        let mut messages = Vec::new();
+        let mut pongs = Vec::new();
+        let mut rejected = None;
+        if let Some(incoming) = &self.incoming {
+            loop {
+                match incoming.try_recv() {
+                    Ok(Err(e)) => {
+                        rejected = Some(e);
+                        break;
+                    }
+                    Ok(Ok(message)) => {
+                        self.stats.received();
+                        match Kind::of(&message) {
+                            // The client said it was ready again while the
+                            // start message was on its way
+                            Ok(Kind::Match) if concat::read_ready(&message) => (),
+                            Ok(Kind::Ping(number)) => pongs.push(number),
+                            Ok(Kind::Pong(number)) => self.stats.pong(number),
+                            Ok(kind) => messages.push((kind, message)),
+                            Err(e) => {
+                                rejected = Some(e);
+                                break;
+                            }
+                        }
+                    }
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        info!("The other player hung up");
+                        self.events.record(self.update_nbr, "disconnected", json!({ "reason": "hung up" }));
+                        self.fail(Error::PeerGone("they hung up".to_string()));
+                        break;
+                    }
+                }
+            }
+        }
+
+        if let Some(e) = rejected {
+            self.reject(e);
+            return Vec::new();
+        }
+
+        for number in pongs {
+            self.send(&Kind::Pong(number).to_bytes());
+        }
+        if self.incoming.is_some() {
+            if let Some(number) = self.stats.ping_due() {
+                self.send(&Kind::Ping(number).to_bytes());
+            }
+        }
+        messages
+    }
+
+    /// Take in what the client sent since last time, on the server. Returns
+    /// whether the client needs our snapshot because its board drifted
+    /// from ours.
+    fn read_from_client(&mut self, tick: u128) -> bool {
+        let mut resync = false;
+        for (kind, message) in self.receive() {
+            let checked = match kind {
+                Kind::Match => match Input::from_bytes(&message) {
+                    // An input too far ahead would sit around for ages, so
+                    // we drop it. The client's clock follows our states, so
+                    // it won't be long before its inputs are back in reach.
+                    Ok(input) if input.tick > tick + MAX_INPUT_LEAD => Ok(()),
+                    Ok(input) => {
+                        self.stats.arrived(input.tick, tick);
+                        self.pending_inputs.insert(input.tick, input.intents);
+                        Ok(())
+                    }
+                    Err(e) => Err(e),
+                },
+                Kind::Desync(at) if at as u128 > tick => Err(ProtocolError::BadTick(at as u128)),
+                // Reports about ticks before our last snapshot got there
+                // were already taken care of
+                Kind::Desync(at) => {
+                    if !matches!(self.resynced_at, Some(resynced) if at as u128 <= resynced) {
+                        warn!("The client's board drifted from ours on tick {}", at);
+                        self.events.record(tick, "desync", json!({ "at": at }));
+                        resync = true;
+                    }
+                    Ok(())
+                }
+                _ => Err(ProtocolError::BadKind(concat::read_kind(&message))),
+            };
+            if let Err(e) = checked {
+                self.reject(e);
+                break;
+            }
+        }
+        resync
+    }
+
+    /// Take in what the server sent since last time, on the client
+    fn read_from_server(&mut self, tick: u128) -> Vec<FromServer> {
+        let mut from_server = Vec::new();
+        let mut confirmed = self.prediction.as_ref().and_then(Prediction::confirmed_tick);
+        // Everything the server sends has to be newer than what came before
+        // it. It can be ahead of us, though, if our window stalled.
+        let out_of_place = |at: u128, confirmed: Option<u128>, newer: bool| {
+            confirmed.is_some_and(|confirmed| at < confirmed || (newer && at == confirmed))
+        };
+
+        for (kind, message) in self.receive() {
+            let checked = match kind {
+                Kind::Match => match TickState::from_bytes(&message) {
+                    Ok(state) if out_of_place(state.tick, confirmed, true) => Err(ProtocolError::BadTick(state.tick)),
+                    Ok(state) => {
+                        self.stats.arrived(state.tick, tick);
+                        confirmed = Some(state.tick);
+                        from_server.push(FromServer::State(state));
+                        Ok(())
+                    }
+                    Err(e) => Err(e),
+                },
+                Kind::Checksum(at, checksum) => {
+                    from_server.push(FromServer::Checksum(at as u128, checksum));
+                    Ok(())
+                }
+                // A snapshot is for the tick the server just sent
+                Kind::Snapshot => match self.snapshot_parts.add(&message) {
+                    Ok(Some(snapshot)) if out_of_place(snapshot.tick, confirmed, false) => {
+                        Err(ProtocolError::BadTick(snapshot.tick))
+                    }
+                    Ok(Some(snapshot)) => {
+                        confirmed = Some(snapshot.tick);
+                        from_server.push(FromServer::Snapshot(snapshot));
+                        Ok(())
+                    }
+                    Ok(None) => Ok(()),
+                    Err(e) => Err(e),
+                },
+                _ => Err(ProtocolError::BadKind(concat::read_kind(&message))),
+            };
+            if let Err(e) = checked {
+                self.reject(e);
+                break;
+            }
+        }
+        from_server
+    }
+
+    /// Everything about the board right now
+    fn snapshot(&self) -> Snapshot {
+        Snapshot::new(&self.world, self.update_nbr)
+    }
+
+    /// Put the board back the way a saved game left it, and count down
+    /// again before the snakes move on
+    fn restore(&mut self, snapshot: Snapshot) {
+        self.world = snapshot.world;
+        self.update_nbr = snapshot.tick;
+        self.start_tick = snapshot.tick + START_TICK as u128;
+        self.logged_steering = [self.world.player1.steering(), self.world.player2.steering()];
+        self.events.record(snapshot.tick, "resume", json!({}));
+    }
+
+    /// Write down which snakes turned since the last tick
+    fn log_inputs(&mut self, tick: u128) {
+        let steering = [self.world.player1.steering(), self.world.player2.steering()];
+        for (player, (now, before)) in steering.iter().zip(self.logged_steering.iter()).enumerate() {
+            if now != before {
+                self.events.record(tick, "input", json!({
+                    "player": player + 1,
+                    "dir": direction_name(now.dir),
+                    "next_dir": now.next_dir.map(direction_name),
+                }));
+            }
+        }
+    }
+
+    /// Write down what the snakes ate on the tick that was just played
+    fn log_step(&mut self, tick: u128) {
+        let snakes = [&self.world.player1, &self.world.player2];
+        let playing = if self.world.solo { 1 } else { 2 };
+        for (player, snake) in snakes.iter().enumerate().take(playing) {
+            let details = json!({
+                "player": player + 1,
+                "at": json::position_to_json(snake.head.pos),
+                "score": snake.score(),
+            });
+            match snake.ate {
+                Some(Ate::Food)   => self.events.record(tick, "food", details),
+                Some(Ate::Itself) => self.events.record(tick, "collision", details),
+                None => (),
+            }
+        }
+        // Snakes turn on their own when they move on to a queued direction,
+        // which nobody pressed a key for
+        self.logged_steering = [self.world.player1.steering(), self.world.player2.steering()];
+    }
+
+    /// Write down how the match ended
+    fn log_game_over(&mut self, tick: u128) {
+        let winner = match self.world.won(Player::One) {
+            Some(true)  => Some(1),
+            Some(false) => Some(2),
+            None        => None,
+        };
+        self.events.record(tick, "gameover", json!({
+            "scores": [self.world.player1.score(), self.world.player2.score()],
+            "winner": winner,
+        }));
+    }
+
+    /// Save the game for later from the pause menu, and close the window.
+    /// If it can't be saved, we stay paused.
+    fn save_and_quit(&mut self, ctx: &mut Context) {
+        let saved = SavedGame { mode: self.mode, snapshot: self.snapshot() };
+        match saved.save() {
+            Ok(()) => event::quit(ctx),
+            Err(e) => error!("Could not save the game: {}", e),
+        }
+    }
+
+    /// Who plays `player`'s snake
+    fn profile(&self, player: Player) -> &Profile {
+        match player {
+            Player::One => &self.profiles[0],
+            Player::Two => &self.profiles[1],
         }
+    }
+
+    /// Whether we're still counting down to the start of the match
+    fn counting_down(&self) -> bool {
+        self.update_nbr < self.start_tick
+    }
 
-        // Then we check to see if the game is over. If not, we'll update. If so,
+    /// How long we wait between updates. Two-player games always run at
+    /// the same speed, but single-player gets faster as the snake grows.
+    /// The client's clock follows the server's: it waits a little longer
+    /// when it got more than its lead ahead of the last state the server
+    /// sent, and a little less when it fell behind.
+    fn millis_per_update(&self) -> u64 {
+        match self.mode {
+            Mode::Single => {
+                let speedup = self.world.player1.score() as u64 * SPEEDUP_MILLIS_PER_FOOD;
+                MILLIS_PER_UPDATE
+                    .saturating_sub(speedup)
+                    .max(MIN_MILLIS_PER_UPDATE)
+            }
+            Mode::Client => {
+                let confirmed = self.prediction.as_ref().and_then(Prediction::confirmed_tick);
+                let target = match confirmed {
+                    Some(confirmed) => confirmed + self.lead,
+                    None => return MILLIS_PER_UPDATE,
+                };
+                let ahead = (self.update_nbr as i64 - target as i64).clamp(-MAX_NUDGES, MAX_NUDGES);
+                (MILLIS_PER_UPDATE as i64 + ahead * NUDGE_MILLIS) as u64
+            }
+            _ => MILLIS_PER_UPDATE,
+        }
+    }
+
+    /// Play the next tick of the match
+    fn tick(&mut self) {
+        // We check to see if the game is over. If not, we'll update. If so,
         // we just do nothing.
-        if !self.gameover {
+        if !self.world.gameover {
+            if !self.counting_down() {
+                let tick = self.update_nbr;
+                let asked = self.world.run_pilots(&mut self.pilots, tick);
+                // A bot playing for the client asks the server like anyone else
+                if let (Mode::Client, Some(dir)) = (self.mode, asked[1]) {
+                    self.intend(dir);
+                }
+            }
+
             match self.mode {
                 Mode::Server => {
-                    // Here we do that actual updating of our game world. First, we tell the
-                    // snake to update itself,
-                    // passing in a reference to our piece of food.
-                    self.player1.update(&self.food);
-                    
-                    // Next, we check if the snake ate anything as it updated.
-                    if let Some(ate) = self.player1.ate {
-                        match ate {
-                            Ate::Food => {
-                                let new_food_pos =
-                                    GridPosition::random(&mut self.rng, GRID_SIZE.0, GRID_SIZE.1);
-                                self.food.pos = new_food_pos;     
-                            }
-                            Ate::Itself => {
-                                self.gameover = true;
+                    // The server is the one running the match. It plays the
+                    // client's inputs as they come in, and never waits for
+                    // them: a late input is played on the tick it arrives.
+                    let tick = self.update_nbr;
+                    let resync = self.read_from_client(tick);
+                    // We steer the client's snake ourselves, with the keys
+                    // its player pressed, so it can only turn where ours could
+                    let later = self.pending_inputs.split_off(&(tick + 1));
+                    for dir in self.pending_inputs.values().flatten() {
+                        self.world.player2.steer(*dir);
+                    }
+                    self.pending_inputs = later;
+
+                    // While we're counting down the snakes stay put, but we
+                    // still send every tick so the client knows where we are.
+                    let steering = [self.world.player1.steering(), self.world.player2.steering()];
+                    if !self.counting_down() {
+                        self.log_inputs(tick);
+                        self.world.step();
+                        self.log_step(tick);
+                    }
+
+                    let state = TickState {
+                        tick,
+                        steering,
+                        food: self.world.food.pos,
+                        gameover: self.world.gameover,
+                    };
+                    self.send(&state.to_bytes());
+
+                    // If the client's board drifted from ours, it gets all
+                    // of ours to start over from, and every now and then,
+                    // we check that it didn't. Copying the board isn't
+                    // cheap, so we only do it on those ticks.
+                    let checksum_due = tick.is_multiple_of(CHECKSUM_EVERY);
+                    if resync || checksum_due {
+                        let snapshot = Snapshot::new(&self.world, tick);
+                        if resync {
+                            for message in messages::snapshot_messages(&snapshot.to_bytes()) {
+                                self.send(&message);
                             }
+                            self.resynced_at = Some(tick);
+                        }
+                        if checksum_due {
+                            self.send(&Kind::Checksum(tick as u32, snapshot.checksum()).to_bytes());
                         }
                     }
-
-                    if let Some(ate) = self.player2.ate {
-                        match ate {
-                            Ate::Food => {
-                                let new_food_pos =
-                                    GridPosition::random(&mut self.rng, GRID_SIZE.0, GRID_SIZE.1);
-                                self.food.pos = new_food_pos;     
+                },
+                Mode::Client => {
+                    let tick = self.update_nbr;
+                    // Catch up on everything the server played since last time
+                    let from_server = self.read_from_server(tick);
+
+                    // Tell the server where we asked to go on this tick
+                    let input = Input { tick, intents: std::mem::take(&mut self.intents) };
+                    self.send(&input.to_bytes());
+                    if !input.intents.is_empty() {
+                        let intents: Vec<&str> = input.intents.iter().map(|dir| direction_name(*dir)).collect();
+                        self.events.record(tick, "input", json!({ "player": 2, "intents": intents }));
+                    }
+                    let lost = self.world.gameover;
+
+                    let prediction = self.prediction.as_mut().expect("the client predicts");
+                    let mut drifted = Vec::new();
+                    for message in from_server {
+                        match message {
+                            FromServer::State(state) => prediction.confirm(&state),
+                            // We can only compare if we have the state for
+                            // that tick, which over UDP might never have come
+                            FromServer::Checksum(at, checksum) => {
+                                if prediction.confirmed_tick() == Some(at)
+                                    && prediction.confirmed().checksum() != checksum {
+                                    warn!("Our board drifted from the server's on tick {}", at);
+                                    self.events.record(tick, "desync", json!({ "at": at as u64 }));
+                                    drifted.push(at);
+                                }
                             }
-                            Ate::Itself => {
-                                self.gameover = true;
+                            FromServer::Snapshot(snapshot) => {
+                                dump_desync(&prediction.confirmed(), &snapshot);
+                                self.events.record(tick, "resync", json!({ "at": snapshot.tick as u64 }));
+                                prediction.resync(snapshot);
                             }
                         }
                     }
+                    prediction.record(tick, input.intents);
+                    // and see where that gets us, starting from what the
+                    // server confirmed
+                    self.world = prediction.predict(tick);
+                    // Only the server decides when the game is over, unless
+                    // we lost the connection to it
+                    self.world.gameover = prediction.gameover() || lost;
+
+                    // If one of us stalled and we're far off from where the
+                    // server says we should be, nudging our clock would take
+                    // ages, so we skip straight there
+                    if let Some(confirmed) = prediction.confirmed_tick() {
+                        let target = confirmed + self.lead;
+                        if target.abs_diff(tick) > MAX_LEAD_TICKS {
+                            info!("Skipping from tick {} to {} to keep up with the server", tick, target);
+                            self.events.record(tick, "skip", json!({ "to": target as u64 }));
+                            // which is where we'll be once this tick is over
+                            self.update_nbr = target - 1;
+                        }
+                    }
 
-                    // Then send the new food location to the client
-                    buffer = concat::add_position(&mut buffer, &self.food.pos.to_bytes());
-                    // we also send if the game is over
-                    buffer = concat::is_game_over(&mut buffer, self.gameover);
-                    // We also want to send the keystroke of player 1 to the client
-                    buffer = concat::write_directions(
-                        &mut buffer, 
-                        self.player1.dir, 
-                        self.player1.last_update_dir,
-                        self.player1.next_dir,
-                    );
-                    // Send it over to the client
-                    self.stream.write(&buffer[0..BUFFER_SIZE]).unwrap();
-
-                    // Read the buffer from the client
-                    self.stream.read_exact(&mut buffer).unwrap();
-                    // And now we read the actions of player2
-                    let (dir, last_update_dir, next_dir) = concat::read_directions(&buffer);
-                    self.player2.dir = dir;
-                    self.player2.last_update_dir = last_update_dir;
-                    self.player2.next_dir = next_dir;
-
-                    self.player2.update(&self.food);
-                },
-                Mode::Client => {
-                    // Client owns player2 so we update player 2 from client
-                    
-                    self.player2.update(&self.food);
-
-                    // We get the new position of the food.
-                    let _ = self.stream.read_exact(&mut buffer).unwrap();
-                    let pos = concat::read_position(&buffer);
-                    let gp = GridPosition::from_bytes(&pos);
-                    
-                    // Also, we read what player 1 did
-                    let (dir, last_update_dir, next_dir) = concat::read_directions(&buffer);
-                    self.player1.dir = dir;
-                    self.player1.last_update_dir = last_update_dir;
-                    self.player1.next_dir = next_dir;
-
-                    self.player1.update(&self.food);
-                    self.food.pos = gp;
-
-                    // We also have to encode the keypresses of player 2
-                    // and send them to the server
-                    buffer = concat::write_directions(
-                        &mut buffer,
-                        self.player2.dir,
-                        self.player2.last_update_dir,
-                        self.player2.next_dir,
-                    );
-
-                    self.stream.write(&buffer[0..BUFFER_SIZE]).unwrap();
+                    for at in drifted {
+                        self.send(&Kind::Desync(at as u32).to_bytes());
+                    }
+                }
+                Mode::Local => {
+                    // Both snakes live on this machine, so there's nobody to
+                    // talk to and we just update them one after the other.
+                    if !self.counting_down() {
+                        let tick = self.update_nbr;
+                        self.log_inputs(tick);
+                        self.world.step();
+                        self.log_step(tick);
+                    }
+                }
+                Mode::Single => {
+                    // There's only the one snake to update
+                    if !self.counting_down() {
+                        let tick = self.update_nbr;
+                        self.log_inputs(tick);
+                        self.world.step();
+                        self.log_step(tick);
+                    }
+
+                    // Only humans make it into the high-score table
+                    if self.world.gameover && self.pilots[0].is_none() {
+                        self.record_high_score();
+                    }
                 }
             } 
+
+            // Whoever runs the match shows it to anyone watching. Copying
+            // the board isn't cheap, so only if someone is.
+            if self.mode != Mode::Client && !self.spectators.is_empty() {
+                let snapshot = self.snapshot();
+                self.spectators.show(&messages::snapshot_messages(&snapshot.to_bytes()));
+            }
+
+            if self.world.gameover {
+                self.world.end_pilots(&mut self.pilots);
+                self.log_game_over(self.update_nbr);
+            }
         }
-        // If we updated, we set our last update to be now
-        self.last_update = Instant::now();
         self.update_nbr += 1;
-        
+    }
+
+    /// Put the score of a finished single-player game into the high-score
+    /// table, if it's good enough.
+    fn record_high_score(&self) {
+        let mut high_scores = HighScores::load();
+        if high_scores.add(self.world.player1.score(), &self.profiles[0].name) {
+            if let Err(e) = high_scores.save() {
+                error!("Could not save the high scores: {}", e);
+            }
+        }
+    }
+
+    /// Draw some text in the middle of the screen
+    fn draw_centered_text(ctx: &mut Context, contents: String, size: f32) -> GameResult<()> {
+        let text = graphics::Text::new((contents, graphics::Font::default(), size));
+        let (width, height) = text.dimensions(ctx);
+        let dest = ggez::mint::Point2 {
+            x: (SCREEN_SIZE.0 - width as f32) / 2.0,
+            y: (SCREEN_SIZE.1 - height as f32) / 2.0,
+        };
+        graphics::draw(ctx, &text, (dest, graphics::BLACK))
+    }
+
+    /// Draw the number of seconds left before the snakes start moving
+    /// in the middle of the screen.
+    fn draw_countdown(&self, ctx: &mut Context) -> GameResult<()> {
+        let ticks_left = (self.start_tick - self.update_nbr) as f32;
+        let seconds_left = (ticks_left / UPDATES_PER_SECOND).ceil() as u32;
+
+        Self::draw_centered_text(ctx, seconds_left.to_string(), 96.0)
+    }
+
+    /// Draw the pause menu in the middle of the screen
+    fn draw_pause_menu(ctx: &mut Context) -> GameResult<()> {
+        Self::draw_centered_text(ctx, "Paused".to_string(), 64.0)?;
+        Self::draw_hint(ctx, "Esc to play on, S to save and quit")
+    }
+
+    /// Say that the game is over, who won, and how to get back to the launcher
+    fn draw_game_over(&self, ctx: &mut Context) -> GameResult<()> {
+        Self::draw_centered_text(ctx, "Game over".to_string(), 64.0)?;
+
+        let result = match (self.mode, self.world.won(Player::One)) {
+            (Mode::Single, _) => graphics::TextFragment::new(format!("Score: {}", self.world.player1.score())),
+            (_, None) => graphics::TextFragment::new("It's a draw"),
+            (_, Some(won)) => {
+                let winner = if won { Player::One } else { Player::Two };
+                let profile = self.profile(winner);
+                graphics::TextFragment::new(format!("{} wins", profile.display_name(winner)))
+                    .color(profile.colour.head())
+            }
+        };
+        let result = graphics::Text::new(result.scale(graphics::Scale::uniform(32.0)));
+        Self::draw_line_below(ctx, result, 48.0)?;
+        Self::draw_hint_below(ctx, "Enter to go back to the launcher", 96.0)
+    }
+
+    /// Draw a line of small print under whatever is in the middle of the screen
+    fn draw_hint(ctx: &mut Context, hint: &str) -> GameResult<()> {
+        Self::draw_hint_below(ctx, hint, 48.0)
+    }
+
+    /// Draw a line of small print `offset` pixels below the middle of the screen
+    fn draw_hint_below(ctx: &mut Context, hint: &str, offset: f32) -> GameResult<()> {
+        let text = graphics::Text::new((
+            hint.to_string(),
+            graphics::Font::default(),
+            24.0,
+        ));
+        Self::draw_line_below(ctx, text, offset)
+    }
+
+    /// Draw `text` across the middle of the screen, `offset` pixels below it
+    fn draw_line_below(ctx: &mut Context, text: graphics::Text, offset: f32) -> GameResult<()> {
+        let (width, _) = text.dimensions(ctx);
+        let dest = ggez::mint::Point2 {
+            x: (SCREEN_SIZE.0 - width as f32) / 2.0,
+            y: SCREEN_SIZE.1 / 2.0 + offset,
+        };
+        graphics::draw(ctx, &text, (dest, graphics::BLACK))
+    }
+
+    /// Draw the scores in the top left corner of the screen, with every
+    /// player's name in their colour
+    fn draw_hud(&self, ctx: &mut Context) -> GameResult<()> {
+        let mut text = graphics::Text::default();
+        let players = match self.mode {
+            Mode::Single => &[Player::One][..],
+            _ => &[Player::One, Player::Two][..],
+        };
+        for (i, player) in players.iter().enumerate() {
+            let profile = self.profile(*player);
+            let snake = match player {
+                Player::One => &self.world.player1,
+                Player::Two => &self.world.player2,
+            };
+            let separator = if i == 0 { "" } else { "   " };
+            text.add(graphics::TextFragment::new(format!("{}{}", separator, profile.display_name(*player)))
+                .color(profile.colour.head()));
+            text.add(format!(": {}", snake.score()));
+        }
+        text.set_font(graphics::Font::default(), graphics::Scale::uniform(24.0));
+        graphics::draw(
+            ctx,
+            &text,
+            (ggez::mint::Point2 { x: 8.0, y: 8.0 }, graphics::BLACK),
+        )
+    }
+}
+
+/// Now we implement EventHandler for GameState. This provides an interface 
+/// that ggez will call automatically when different events happen.
+impl event::EventHandler for GameState {
+    /// Update will happen on every frame before it is drawn. This is where
+    /// we update our game state to react to whatever is happening in the game
+    /// world.
+    fn update(&mut self, _ctx: &mut Context) -> GameResult {
+        // Nothing moves while the pause menu is up
+        if self.paused {
+            return Ok(());
+        }
+
+        // Then we check to see if enough time has elapsed since the last
+        // tick was due, based on the update rate we defined at the top. We
+        // count from when it was due rather than from when a frame came
+        // around to it, so that the match keeps the same pace as the other
+        // player's, and after a stall we catch up on the ticks we missed, a
+        // few on every frame.
+        let local = matches!(self.mode, Mode::Local | Mode::Single);
+        for _ in 0..MAX_TICKS_PER_FRAME {
+            let due = Duration::from_millis(self.millis_per_update());
+            if self.last_update.elapsed() < due {
+                break;
+            }
+            self.last_update += due;
+            self.tick();
+
+            // Local games have nobody to keep up with, so rather than
+            // rushing the snakes through a stall, we forget it
+            if local && self.last_update.elapsed() >= due {
+                self.last_update = Instant::now();
+            }
+        }
+
         Ok(())
     }
 
+
     /// The draw is where we should actually render the game's current state.
     fn draw(&mut self, ctx: &mut Context) -> GameResult {
         // First we clear the screen to a nice (well, maybe pretty glaring ;)) green
         graphics::clear(ctx, [0.0, 1.0, 0.0, 1.0].into());
-        // Then we tell the snake and the food to draw themselves.
-        self.player1.draw(ctx)?;
-        self.player2.draw(ctx)?;
-        self.food.draw(ctx)?;
+        // Then we tell the snakes and the food to draw themselves. The client
+        // draws the opponent where the server last said it was.
+        match &self.prediction {
+            Some(prediction) => {
+                self.world.player2.draw(ctx, &self.profiles[1])?;
+                self.world.food.draw(ctx)?;
+                prediction.draw_opponent(ctx, &self.profiles[0])?;
+            }
+            None => self.world.draw(ctx, &self.profiles)?,
+        }
+        // and put the scores on top of everything
+        self.draw_hud(ctx)?;
+        // and, if asked for, how the connection is doing
+        if self.show_stats && self.link.is_some() {
+            self.stats.draw(ctx)?;
+        }
+        // While counting down, we show how long is left until the start
+        if self.counting_down() {
+            self.draw_countdown(ctx)?;
+        }
+        // and once it's over, we say so
+        if self.world.gameover {
+            self.draw_game_over(ctx)?;
+        } else if self.paused {
+            Self::draw_pause_menu(ctx)?;
+        }
         // Finally, we call graphics::present to cycle the gpu's framebuffer
         // and display the new frame we just drew.
         graphics::present(ctx)?;
@@ -671,40 +1557,163 @@ impl event::EventHandler for GameState {
     /// key_down_event gets fired when a key gets pressed
     fn key_down_event(
         &mut self,
-        _ctx: &mut Context,
+        ctx: &mut Context,
         keycode: KeyCode,
         _keymod: KeyMods,
         _repeat: bool) {
         
-        // Here we attempt to convert the KeyCode into a direction
-        if let Some(dir) = Direction::from_keycode(keycode) {
-            // if it succeeds, we check if the new direction has already been set.
-            // and make sure the new idrection is different then `snake.dir`
-            match self.mode {
-                Mode::Server => {
-                    if self.player1.dir != self.player1.last_update_dir && dir.inverse() != self.player1.dir {
-                        self.player1.next_dir = Some(dir);
-                    } else if dir.inverse() != self.player1.last_update_dir {
-                        // If no new direction has been set and the direction is not the inverse,
-                        // of the last_update_dir, then set the snake's new direction to be
-                        // the direction the user pressed.
-                        self.player1.dir = dir;
-                        // Also, we send this to the client
-                        // let buffer = dir.to_bytes();
-                        // let _ = self.stream.write(&buffer).unwrap();
-                    }
+        // Local games can be paused with Esc, and saved from the pause menu.
+        // Networked games can't wait for one player.
+        let local = matches!(self.mode, Mode::Local | Mode::Single);
+        if local && !self.world.gameover && keycode == KeyCode::Escape {
+            self.paused = !self.paused;
+            // so that the snakes don't jump ahead when we play on
+            self.last_update = Instant::now();
+            return;
+        }
+        if self.paused {
+            if keycode == KeyCode::S {
+                self.save_and_quit(ctx);
+            }
+            return;
+        }
+        // Once the game is over, the players can go back to the launcher
+        // for another one
+        if self.world.gameover && matches!(keycode, KeyCode::Return | KeyCode::Escape) {
+            event::quit(ctx);
+            return;
+        }
+
+        // F3 shows or hides the connection numbers
+        if keycode == KeyCode::F3 {
+            self.show_stats = !self.show_stats;
+            return;
+        }
+        // and F4 writes the whole board to disk, for debugging
+        if keycode == KeyCode::F4 {
+            match self.snapshot().dump(&format!("board-{}", self.update_nbr)) {
+                Ok(path) => info!("Dumped the board to {}", path.display()),
+                Err(e) => error!("Could not dump the board: {}", e),
+            }
+            return;
+        }
+
+        // Here we attempt to convert the KeyCode into a direction using the
+        // bindings of whoever steers each snake on this machine. Snakes that
+        // a bot steers don't listen to the keyboard.
+        match self.mode {
+            // The server plays the first snake, as does the only player
+            // in single-player
+            Mode::Server | Mode::Single => {
+                match self.bindings[0].direction(keycode) {
+                    Some(dir) if self.pilots[0].is_none() => self.world.player1.steer(dir),
+                    _ => (),
                 }
-                Mode::Client => {
-                    if self.player2.dir != self.player2.last_update_dir && dir.inverse() != self.player2.dir {
-                        self.player2.next_dir = Some(dir);
-                    } else if dir.inverse() != self.player2.last_update_dir {
-                        // If no new direction has been set and the direction is not the inverse,
-                        // of the last_update_dir, then set the snake's new direction to be
-                        // the direction the user pressed.
-                        self.player2.dir = dir;
-                    }
+            }
+            // and the client plays the second one, which only the server
+            // steers. We just tell it which way we want to go.
+            Mode::Client => {
+                match self.bindings[0].direction(keycode) {
+                    Some(dir) if self.pilots[1].is_none() => self.intend(dir),
+                    _ => (),
+                }
+            }
+            // while in a local game, both players share the keyboard
+            Mode::Local => {
+                if let Some(dir) = self.bindings[0].direction(keycode) {
+                    self.world.player1.steer(dir);
+                }
+                match self.bindings[1].direction(keycode) {
+                    Some(dir) if self.pilots[1].is_none() => self.world.player2.steer(dir),
+                    _ => (),
                 }
             }
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::VecDeque;
+    use std::net::SocketAddr;
+
+    /// A server that says what it was told to, whatever the client sends
+    struct Scripted(VecDeque<[u8; BUFFER_SIZE]>);
+
+    impl Link for Scripted {
+        fn send(&mut self, _message: &[u8; BUFFER_SIZE]) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn receive_within(&mut self, _timeout: Duration) -> io::Result<Option<[u8; BUFFER_SIZE]>> {
+            Ok(self.0.pop_front())
+        }
+
+        fn receive(&mut self) -> io::Result<[u8; BUFFER_SIZE]> {
+            self.0.pop_front().ok_or_else(|| io::ErrorKind::UnexpectedEof.into())
+        }
+
+        fn try_clone(&self) -> io::Result<Box<dyn Link>> {
+            Ok(Box::new(Scripted(self.0.clone())))
+        }
+
+        fn peer_addr(&self) -> Option<SocketAddr> {
+            None
+        }
+
+        fn transport(&self) -> &'static str {
+            "scripted"
+        }
+    }
+
+    /// What a client gets out of joining a server that sends `messages`
+    fn join(messages: Vec<[u8; BUFFER_SIZE]>) -> Option<ProtocolError> {
+        let bindings = [KeyBindings::from_layout(Layout::Arrows), KeyBindings::from_layout(Layout::Wasd)];
+        let profile = Profile::default_for(Player::Two);
+        let link: Box<dyn Link> = Box::new(Scripted(messages.into()));
+        let e = GameState::new(Mode::Client, Some(link), bindings, profile, None, None, &|| false).err()?;
+        e.get_ref()?.downcast_ref::<ProtocolError>().copied()
+    }
+
+    fn start_message(start_tick: u32) -> [u8; BUFFER_SIZE] {
+        let mut buffer = [0; BUFFER_SIZE];
+        buffer = concat::write_start_tick(&mut buffer, start_tick);
+        concat::add_position(&mut buffer, &GridPosition::new(3, 7).to_bytes())
+    }
+
+    #[test]
+    fn directions_that_arent_one_are_rejected() {
+        assert_eq!(Direction::from_bytes(&[3]), Ok(Direction::Right));
+        assert_eq!(Direction::from_bytes(&[4]), Err(ProtocolError::BadDirection(4)));
+    }
+
+    #[test]
+    fn positions_off_the_board_are_rejected() {
+        let on_board = GridPosition::new(GRID_SIZE.0 - 1, GRID_SIZE.1 - 1);
+        assert_eq!(GridPosition::from_bytes(&on_board.to_bytes()), Ok(on_board));
+
+        for &(x, y) in &[(-1, 0), (0, -1), (GRID_SIZE.0, 0), (0, GRID_SIZE.1)] {
+            let bytes = GridPosition::new(x, y).to_bytes();
+            assert_eq!(GridPosition::from_bytes(&bytes), Err(ProtocolError::OffTheBoard(x, y)));
+        }
+    }
+
+    #[test]
+    fn a_client_rejects_a_start_tick_out_of_range() {
+        let tick = MAX_START_TICK + 1;
+        assert_eq!(join(vec![start_message(tick)]), Some(ProtocolError::BadTick(tick as u128)));
+    }
+
+    #[test]
+    fn a_client_rejects_a_start_message_of_another_kind() {
+        let ping = Kind::Ping(1).to_bytes();
+        assert_eq!(join(vec![ping]), Some(ProtocolError::BadKind(1)));
+    }
+
+    #[test]
+    fn a_client_rejects_anything_but_the_servers_player_after_the_start() {
+        let pong = Kind::Pong(1).to_bytes();
+        assert_eq!(join(vec![start_message(START_TICK), pong]), Some(ProtocolError::BadKind(2)));
+    }
+}
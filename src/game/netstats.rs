@@ -0,0 +1,122 @@
+use ggez::{graphics, Context, GameResult};
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use super::{BUFFER_SIZE, SCREEN_SIZE};
+
+/// How often we ping the other player
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+/// Pings that haven't been answered after this long are forgotten
+const PING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What we know about how the connection to the other player is doing
+pub struct NetStats {
+    /// The last round trip time we measured
+    rtt: Option<Duration>,
+    /// How much the round trip time varies, smoothed the same way RTP does
+    jitter: Duration,
+    /// How many of our messages went each way. Every one is `BUFFER_SIZE`
+    /// bytes, but what that takes on the wire depends on the link: UDP
+    /// sends it several times, encryption doubles it, and WebSocket frames
+    /// it, so this is no measure of bandwidth.
+    messages_sent: u64,
+    messages_received: u64,
+    /// How many messages for a tick came in after that tick was already played
+    late_ticks: u64,
+    /// How many ticks a message may trail ours before it counts as late
+    late_after: u128,
+    /// The pings we're still waiting on a pong for, and when we sent them
+    pings: BTreeMap<u32, Instant>,
+    next_ping: u32,
+    last_ping: Instant,
+}
+
+impl NetStats {
+    pub fn new(late_after: u128) -> Self {
+        NetStats {
+            rtt: None,
+            jitter: Duration::from_millis(0),
+            messages_sent: 0,
+            messages_received: 0,
+            late_ticks: 0,
+            late_after,
+            pings: BTreeMap::new(),
+            next_ping: 0,
+            last_ping: Instant::now(),
+        }
+    }
+
+    pub fn sent(&mut self) {
+        self.messages_sent += 1;
+    }
+
+    pub fn received(&mut self) {
+        self.messages_received += 1;
+    }
+
+    /// Note a message for `message_tick` that came in on `tick`
+    pub fn arrived(&mut self, message_tick: u128, tick: u128) {
+        if message_tick + self.late_after < tick {
+            self.late_ticks += 1;
+        }
+    }
+
+    /// If it's time for another ping, the number to send it with
+    pub fn ping_due(&mut self) -> Option<u32> {
+        if self.last_ping.elapsed() < PING_INTERVAL {
+            return None;
+        }
+
+        let number = self.next_ping;
+        self.next_ping = self.next_ping.wrapping_add(1);
+        self.last_ping = Instant::now();
+        self.pings.insert(number, self.last_ping);
+        self.pings.retain(|_, sent| sent.elapsed() < PING_TIMEOUT);
+
+        Some(number)
+    }
+
+    /// The other player answered the ping with `number`
+    pub fn pong(&mut self, number: u32) {
+        let rtt = match self.pings.remove(&number) {
+            Some(sent) => sent.elapsed(),
+            None       => return,
+        };
+
+        if let Some(last) = self.rtt {
+            let difference = rtt.abs_diff(last);
+            // J += (|D| - J) / 16
+            let jitter = self.jitter.as_secs_f64();
+            let jitter = jitter + (difference.as_secs_f64() - jitter) / 16.0;
+            self.jitter = Duration::from_secs_f64(jitter.max(0.0));
+        }
+        self.rtt = Some(rtt);
+    }
+
+    /// Draw the numbers in the top right corner of the screen
+    pub fn draw(&self, ctx: &mut Context) -> GameResult<()> {
+        let rtt = match self.rtt {
+            Some(rtt) => format!("{} ms", rtt.as_millis()),
+            None      => "-".to_string(),
+        };
+        let contents = format!(
+            "ping: {}\njitter: {:.1} ms\nsent: {} msgs × {} B\nreceived: {} msgs × {} B\nlate ticks: {}",
+            rtt,
+            self.jitter.as_secs_f64() * 1000.0,
+            self.messages_sent,
+            BUFFER_SIZE,
+            self.messages_received,
+            BUFFER_SIZE,
+            self.late_ticks,
+        );
+
+        let text = graphics::Text::new((contents, graphics::Font::default(), 16.0));
+        let (width, _) = text.dimensions(ctx);
+        graphics::draw(
+            ctx,
+            &text,
+            (ggez::mint::Point2 { x: SCREEN_SIZE.0 - width as f32 - 8.0, y: 8.0 }, graphics::BLACK),
+        )
+    }
+}
@@ -0,0 +1,165 @@
+//! On a slow connection, waiting for the server before moving our own snake
+//! makes the game feel sluggish. So the client runs a few ticks ahead of the
+//! server: it moves its own snake right away, remembers how it steered on
+//! every tick, and once the server's state for a tick comes in, it starts
+//! over from there and replays whatever the server hasn't seen yet. The
+//! opponent's snake is only ever drawn where the server said it was, sliding
+//! smoothly from one confirmed tick to the next.
+
+use ggez::{Context, GameResult};
+
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+use super::messages::TickState;
+use super::profile::Profile;
+use super::snapshot::Snapshot;
+use super::world::World;
+use super::{Direction, Snake, MILLIS_PER_UPDATE};
+
+/// The most ticks the client runs ahead of the server, however slow the
+/// connection is. Beyond that, the corrections get more annoying than the
+/// delay they hide.
+pub const MAX_LEAD_TICKS: u128 = 8;
+
+/// What the client knows for sure, and what it did since
+pub struct Prediction {
+    /// The board after the last tick the server told us about
+    confirmed: World,
+    /// which is this tick, or `None` before the server said anything
+    confirmed_tick: Option<u128>,
+    /// When the last state from the server came in
+    confirmed_at: Instant,
+    /// The opponent's snake one confirmed tick earlier, which we slide from
+    previous_opponent: Snake,
+    /// Where we asked our snake to go on the ticks the server hasn't
+    /// confirmed yet
+    inputs: BTreeMap<u128, Vec<Direction>>,
+    /// The tick on which the snakes start moving
+    start_tick: u128,
+}
+
+impl Prediction {
+    pub fn new(world: &World, start_tick: u128) -> Self {
+        Prediction {
+            confirmed: world.clone(),
+            confirmed_tick: None,
+            confirmed_at: Instant::now(),
+            previous_opponent: world.player1.clone(),
+            inputs: BTreeMap::new(),
+            start_tick,
+        }
+    }
+
+    /// Remember where we asked our snake to go on `tick`
+    pub fn record(&mut self, tick: u128, intents: Vec<Direction>) {
+        self.inputs.insert(tick, intents);
+    }
+
+    /// The last tick the server told us about
+    pub fn confirmed_tick(&self) -> Option<u128> {
+        self.confirmed_tick
+    }
+
+    /// What the board looked like after the last tick the server told us about
+    pub fn confirmed(&self) -> Snapshot {
+        Snapshot::new(&self.confirmed, self.confirmed_tick.unwrap_or(0))
+    }
+
+    /// Start over from the server's snapshot, after we found out that we
+    /// didn't agree with it anymore
+    pub fn resync(&mut self, snapshot: Snapshot) {
+        self.previous_opponent = snapshot.world.player1.clone();
+        self.confirmed = snapshot.world;
+        self.confirmed_tick = Some(snapshot.tick);
+        self.confirmed_at = Instant::now();
+        self.inputs = self.inputs.split_off(&(snapshot.tick + 1));
+    }
+
+    /// Whether the server said the game is over
+    pub fn gameover(&self) -> bool {
+        self.confirmed.gameover
+    }
+
+    /// Take in the server's state after a tick. States come in order, but
+    /// over UDP some of them may never come at all.
+    pub fn confirm(&mut self, state: &TickState) {
+        self.previous_opponent = self.confirmed.player1.clone();
+
+        // We can't know how the snakes were steered on the ticks we missed,
+        // so we guess they kept going the way they were
+        if let Some(confirmed) = self.confirmed_tick {
+            for missed in confirmed + 1..state.tick {
+                if missed >= self.start_tick {
+                    self.confirmed.step();
+                }
+            }
+        }
+
+        // We play the tick just like the server did, so that our RNG keeps
+        // up with its
+        self.confirmed.player1.set_steering(state.steering[0]);
+        self.confirmed.player2.set_steering(state.steering[1]);
+        if state.tick >= self.start_tick {
+            self.confirmed.step();
+        }
+        // The server decides where the food goes and when the game ends
+        self.confirmed.food.pos = state.food;
+        self.confirmed.gameover = state.gameover;
+
+        self.confirmed_tick = Some(state.tick);
+        self.confirmed_at = Instant::now();
+        // The server has seen every input up to this tick
+        self.inputs = self.inputs.split_off(&(state.tick + 1));
+    }
+
+    /// Start over from the last confirmed tick and replay our own inputs up
+    /// to and including `tick`, to see where the snakes should be by now.
+    pub fn predict(&self, tick: u128) -> World {
+        let mut world = self.confirmed.clone();
+        let first = self.confirmed_tick.map_or(0, |confirmed| confirmed + 1);
+
+        for t in first..=tick {
+            // The server will steer our snake just like this, once our
+            // input gets there
+            for dir in self.inputs.get(&t).into_iter().flatten() {
+                world.player2.steer(*dir);
+            }
+            // We can't know what the opponent does, so we guess it keeps
+            // going the way it was
+            if t >= self.start_tick {
+                world.player1.update(&world.food);
+                world.player2.update(&world.food);
+            }
+        }
+
+        world
+    }
+
+    /// Draw the opponent's snake the way its player wants it to look,
+    /// somewhere between its last two confirmed positions, depending on how
+    /// long ago the last one came in.
+    pub fn draw_opponent(&self, ctx: &mut Context, profile: &Profile) -> GameResult<()> {
+        let elapsed = self.confirmed_at.elapsed().as_millis() as f32;
+        let progress = (elapsed / MILLIS_PER_UPDATE as f32).min(1.0);
+        let current = &self.confirmed.player1;
+
+        let from = self.previous_opponent.segments();
+        let cells: Vec<(f32, f32)> = current
+            .segments()
+            .iter()
+            .enumerate()
+            .map(|(i, seg)| match from.get(i) {
+                // A segment that wrapped around the edge of the board would
+                // slide across all of it, so it just jumps instead
+                Some(prev) if (prev.x - seg.x).abs() <= 1 && (prev.y - seg.y).abs() <= 1 => (
+                    prev.x as f32 + (seg.x - prev.x) as f32 * progress,
+                    prev.y as f32 + (seg.y - prev.y) as f32 * progress,
+                ),
+                _ => (seg.x as f32, seg.y as f32),
+            })
+            .collect();
+
+        profile.skin.draw(ctx, &cells, current.dir, profile.colour)
+    }
+}
@@ -0,0 +1,171 @@
+//! Who plays which snake: the name they go by, and the colour and skin they
+//! picked.
+//! In a networked match, both sides tell each other theirs before the match
+//! starts, and the server makes sure the two snakes don't look the same.
+
+use druid::Data;
+use ggez::graphics;
+
+use super::skin::Skin;
+use super::{Player, BUFFER_SIZE};
+
+/// The most bytes of a name that fit in a message, see `concat::write_profile`
+pub const MAX_NAME_BYTES: usize = BUFFER_SIZE - 6;
+
+/// The colours the launcher offers, by name
+pub const PALETTE: [(&str, Colour); 8] = [
+    ("orange", Colour::rgb(255, 128, 0)),
+    ("green",  Colour::rgb(77, 179, 51)),
+    ("red",    Colour::rgb(215, 38, 38)),
+    ("purple", Colour::rgb(140, 60, 200)),
+    ("yellow", Colour::rgb(240, 220, 40)),
+    ("pink",   Colour::rgb(250, 120, 190)),
+    ("white",  Colour::rgb(245, 245, 245)),
+    ("black",  Colour::rgb(30, 30, 30)),
+];
+
+/// How far apart two colours have to be, as the distance between their
+/// red, green and blue, for us to tell the snakes apart
+const MIN_DISTANCE: u32 = 120;
+
+/// How much darker than its body a snake's head is
+const HEAD_SHADE: f32 = 0.35;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Data)]
+pub struct Colour {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Colour {
+    pub const fn rgb(r: u8, g: u8, b: u8) -> Self {
+        Colour { r, g, b }
+    }
+
+    /// The colour every snake has until its player picks another one
+    pub(super) fn default_for(player: Player) -> Self {
+        match player {
+            Player::One => PALETTE[0].1,
+            Player::Two => PALETTE[1].1,
+        }
+    }
+
+    /// The colour called `name` in the palette, or written as `#rrggbb`
+    pub fn from_name(name: &str) -> Option<Self> {
+        if let Some((_, colour)) = PALETTE.iter().find(|(known, _)| known.eq_ignore_ascii_case(name)) {
+            return Some(*colour);
+        }
+        let hex = name.strip_prefix('#').filter(|hex| hex.len() == 6)?;
+        let part = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+        Some(Colour::rgb(part(0)?, part(2)?, part(4)?))
+    }
+
+    /// The colour's name in the palette, or `#rrggbb` if it isn't in there
+    pub fn name(self) -> String {
+        match PALETTE.iter().find(|(_, colour)| *colour == self) {
+            Some((name, _)) => name.to_string(),
+            None => format!("#{:02x}{:02x}{:02x}", self.r, self.g, self.b),
+        }
+    }
+
+    /// The colour that comes after this one in the launcher
+    pub fn cycle(self) -> Self {
+        let next = PALETTE
+            .iter()
+            .position(|(_, colour)| *colour == self)
+            .map_or(0, |i| (i + 1) % PALETTE.len());
+        PALETTE[next].1
+    }
+
+    /// Whether a snake in this colour is too hard to tell from one in `other`
+    pub fn clashes(self, other: Colour) -> bool {
+        let d = |a: u8, b: u8| (a as i32 - b as i32).pow(2) as u32;
+        d(self.r, other.r) + d(self.g, other.g) + d(self.b, other.b) < MIN_DISTANCE * MIN_DISTANCE
+    }
+
+    /// The colour the second snake gets when its player wants `wanted` and
+    /// the first snake already is `taken`: what they wanted if it's far
+    /// enough from the first snake, or else the first colour in the palette
+    /// that is.
+    pub fn resolve(taken: Colour, wanted: Colour) -> Colour {
+        if !wanted.clashes(taken) {
+            return wanted;
+        }
+        PALETTE
+            .iter()
+            .map(|(_, colour)| *colour)
+            .find(|colour| !colour.clashes(taken))
+            .unwrap_or(wanted)
+    }
+
+    pub fn to_bytes(self) -> [u8; 3] {
+        [self.r, self.g, self.b]
+    }
+
+    pub fn from_bytes(bytes: [u8; 3]) -> Self {
+        Colour::rgb(bytes[0], bytes[1], bytes[2])
+    }
+
+    pub fn body(self) -> graphics::Color {
+        graphics::Color::from_rgb(self.r, self.g, self.b)
+    }
+
+    pub fn head(self) -> graphics::Color {
+        let shade = |c: u8| c as f32 / 255.0 * HEAD_SHADE;
+        graphics::Color::new(shade(self.r), shade(self.g), shade(self.b), 1.0)
+    }
+}
+
+/// A player, as the other players see them
+#[derive(Clone, Debug, PartialEq)]
+pub struct Profile {
+    pub name: String,
+    pub colour: Colour,
+    pub skin: Skin,
+}
+
+impl Profile {
+    pub fn new(name: String, colour: Colour, skin: Skin) -> Self {
+        Profile { name, colour, skin }
+    }
+
+    /// Whoever plays `player` when we know nothing else about them
+    pub(super) fn default_for(player: Player) -> Self {
+        Profile::new(String::new(), Colour::default_for(player), Skin::default())
+    }
+
+    /// The name to show for the player of `player`, who might not have
+    /// given one
+    pub(super) fn display_name(&self, player: Player) -> String {
+        match (self.name.trim(), player) {
+            ("", Player::One) => "P1".to_string(),
+            ("", Player::Two) => "P2".to_string(),
+            (name, _) => name.to_string(),
+        }
+    }
+}
+
+/// A name someone else picked, the way we show it: without control
+/// characters, and cut to as much as fits in a message
+pub fn clean_name(name: &str) -> String {
+    let mut name: String = name.chars().filter(|c| !c.is_control()).collect();
+    let mut end = name.len().min(MAX_NAME_BYTES);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    name.truncate(end);
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_lose_control_characters_and_what_doesnt_fit() {
+        assert_eq!(clean_name("gr\u{1b}[2Jeedy"), "gr[2Jeedy");
+        assert_eq!(clean_name("a bot with a long name"), "a bot with");
+        assert_eq!(clean_name("ñññññññ"), "ñññññ");
+    }
+}
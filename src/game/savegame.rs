@@ -0,0 +1,84 @@
+//! A single-player or hot-seat game that was saved from the pause menu, to
+//! be picked up again later from the launcher. There's only ever one, which
+//! is gone again once it's resumed, so that nobody can try the same game
+//! over and over for a better score. On disk, it's JSON with the mode and
+//! the snapshot of the board, see `snapshot` for what that looks like:
+//!
+//! ```text
+//! { "mode": "single", "board": { "tick": 120, ... } }
+//! ```
+
+use log::warn;
+use serde_json::{json, Value};
+
+use std::fs;
+use std::io;
+
+use crate::config::app_dir;
+use crate::Mode;
+
+use super::snapshot::Snapshot;
+
+/// The file we keep the saved game in, inside our config directory
+const SAVED_GAME_FILE: &str = "savedgame.json";
+
+pub struct SavedGame {
+    pub(super) mode: Mode,
+    pub(super) snapshot: Snapshot,
+}
+
+impl SavedGame {
+    /// The saved game, if there is one we can make sense of. The file might
+    /// have been edited or cut short, so the board has to add up like one
+    /// from the other player would, or the launcher would trip over it.
+    pub fn load() -> Option<Self> {
+        let contents = fs::read_to_string(app_dir().join(SAVED_GAME_FILE)).ok()?;
+        let value: Value = serde_json::from_str(&contents).ok()?;
+
+        let mode = match value.get("mode").and_then(Value::as_str).and_then(Mode::from_name)? {
+            mode @ (Mode::Single | Mode::Local) => mode,
+            _ => return None,
+        };
+        let snapshot = match Snapshot::from_json(value.get("board")?) {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                warn!("Ignoring the saved game: {}", e);
+                return None;
+            }
+        };
+        Some(SavedGame { mode, snapshot })
+    }
+
+    /// Load the saved game and remove it from disk, since it's about to be
+    /// played on
+    pub fn take() -> Option<Self> {
+        let saved = Self::load();
+        let _ = fs::remove_file(app_dir().join(SAVED_GAME_FILE));
+        saved
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let mode = match self.mode {
+            Mode::Single | Mode::Local => self.mode.name(),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "only local games can be saved")),
+        };
+
+        let dir = app_dir();
+        fs::create_dir_all(&dir)?;
+        let contents = serde_json::to_string_pretty(&json!({
+            "mode": mode,
+            "board": self.snapshot.to_json(),
+        }))
+        .map_err(io::Error::other)?;
+        fs::write(dir.join(SAVED_GAME_FILE), contents)
+    }
+
+    /// What the launcher says about the saved game
+    pub fn describe(&self) -> String {
+        let world = &self.snapshot.world;
+        match self.mode {
+            Mode::Single => format!("single player, score {}", world.player1.score()),
+            _ => format!("hot-seat, {} to {}", world.player1.score(), world.player2.score()),
+        }
+    }
+}
@@ -0,0 +1,432 @@
+//! Matches with a passphrase. Before anything else is said, both sides
+//! prove to each other that they know the passphrase, without ever sending
+//! it, and then encrypt everything that follows with keys that are new for
+//! every match.
+//!
+//! 1. The client sends 0xA5, then 14 random bytes, with `KIND_AUTH` in the
+//!    last byte. The server tells it from a bot or a WebSocket client by
+//!    that first byte, so it can't be random.
+//! 2. The server answers the same way with 14 random bytes of its own.
+//! 3. Both sides stretch the passphrase into a key with PBKDF2, salted with
+//!    both sides' random bytes, and the client sends HMAC-SHA256 of both,
+//!    keyed with that, in two messages.
+//! 4. The server checks it, hanging up if it's wrong, and sends its own
+//!    HMAC of both the same way, which the client checks in turn.
+//!
+//! From then on, every message is encrypted with ChaCha20-Poly1305, using a
+//! key for each direction that comes from both sides' random bytes, and a
+//! nonce that counts the messages. Encrypting adds a 16-byte tag, so every
+//! message goes over the link as two.
+//!
+//! Someone who saw a handshake could still try guessing the passphrase on
+//! their own machine. The stretching makes every guess slow, and the salt
+//! means every handshake has to be guessed on its own, but a long
+//! passphrase is still the best defence.
+
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::messages::KIND_AUTH;
+use super::{concat, Link, BUFFER_SIZE};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// How long either side waits for the other's part of the handshake. A
+/// server without a passphrase never answers at all.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How many rounds of PBKDF2 the passphrase goes through. Both sides have to
+/// agree on it, so changing it breaks matches with older versions.
+const KEY_ROUNDS: u32 = 200_000;
+/// The first byte of the message that starts the handshake
+pub const AUTH_MARKER: u8 = 0xA5;
+/// How many random bytes each side puts in, between the marker and the kind
+const NONCE_SIZE: usize = BUFFER_SIZE - 2;
+
+/// A link where everything is encrypted
+pub struct SecureLink {
+    inner: Box<dyn Link>,
+    sending: ChaCha20Poly1305,
+    receiving: ChaCha20Poly1305,
+    /// How many messages went each way, which both sides share between
+    /// their clones of the link
+    counters: Arc<Mutex<Counters>>,
+}
+
+#[derive(Default)]
+struct Counters {
+    sent: u64,
+    received: u64,
+}
+
+/// Prove to the server that we know the passphrase, and check that it does too.
+pub fn client_handshake(mut link: Box<dyn Link>, passphrase: &str) -> io::Result<SecureLink> {
+    let ours = random_nonce()?;
+    link.send(&nonce_message(&ours))?;
+
+    let theirs = match link.receive_within(HANDSHAKE_TIMEOUT)? {
+        Some(message) if is_nonce_message(&message) => read_nonce(&message),
+        _ => return Err(refused("the server doesn't use a passphrase")),
+    };
+
+    let key = passphrase_key(passphrase, &ours, &theirs);
+    send_proof(&mut *link, &proof(&key, b"client", &ours, &theirs))?;
+    let answer = receive_proof(&mut *link).map_err(|_| refused("the server doesn't know the passphrase"))?;
+    check_proof(&key, b"server", &ours, &theirs, &answer)
+        .map_err(|_| refused("the server doesn't know the passphrase"))?;
+
+    Ok(SecureLink::new(link, &key, &ours, &theirs, b"client to server", b"server to client"))
+}
+
+/// Check that the client that just connected knows the passphrase, and
+/// prove to it that we do too.
+pub fn server_handshake(mut link: Box<dyn Link>, passphrase: &str) -> io::Result<SecureLink> {
+    let theirs = match link.receive_within(HANDSHAKE_TIMEOUT)? {
+        Some(message) if is_nonce_message(&message) => read_nonce(&message),
+        _ => return Err(refused("the client didn't give a passphrase")),
+    };
+    let ours = random_nonce()?;
+    link.send(&nonce_message(&ours))?;
+
+    let key = passphrase_key(passphrase, &theirs, &ours);
+    let claimed = receive_proof(&mut *link)?;
+    check_proof(&key, b"client", &theirs, &ours, &claimed)
+        .map_err(|_| refused("the client doesn't know the passphrase"))?;
+    send_proof(&mut *link, &proof(&key, b"server", &theirs, &ours))?;
+
+    Ok(SecureLink::new(link, &key, &theirs, &ours, b"server to client", b"client to server"))
+}
+
+impl SecureLink {
+    fn new(
+        inner: Box<dyn Link>,
+        key: &[u8],
+        client: &[u8; NONCE_SIZE],
+        server: &[u8; NONCE_SIZE],
+        sending: &[u8],
+        receiving: &[u8],
+    ) -> Self {
+        let cipher = |label: &[u8]| {
+            let session_key = proof(key, label, client, server);
+            ChaCha20Poly1305::new(Key::from_slice(&session_key))
+        };
+
+        SecureLink {
+            inner,
+            sending: cipher(sending),
+            receiving: cipher(receiving),
+            counters: Arc::new(Mutex::new(Counters::default())),
+        }
+    }
+}
+
+impl Link for SecureLink {
+    fn send(&mut self, message: &[u8; BUFFER_SIZE]) -> io::Result<()> {
+        let ciphertext = {
+            let mut counters = self.counters.lock().unwrap();
+            let ciphertext = self
+                .sending
+                .encrypt(&nonce(counters.sent), &message[..])
+                .map_err(|_| io::Error::other("could not encrypt a message"))?;
+            counters.sent += 1;
+            ciphertext
+        };
+
+        for half in ciphertext.chunks_exact(BUFFER_SIZE) {
+            let mut part = [0; BUFFER_SIZE];
+            part.copy_from_slice(half);
+            self.inner.send(&part)?;
+        }
+        Ok(())
+    }
+
+    fn receive_within(&mut self, timeout: Duration) -> io::Result<Option<[u8; BUFFER_SIZE]>> {
+        let first = match self.inner.receive_within(timeout)? {
+            Some(first) => first,
+            None => return Ok(None),
+        };
+        // The second half is already on its way
+        let second = self.inner.receive()?;
+        self.decrypt(&first, &second).map(Some)
+    }
+
+    fn receive(&mut self) -> io::Result<[u8; BUFFER_SIZE]> {
+        let first = self.inner.receive()?;
+        let second = self.inner.receive()?;
+        self.decrypt(&first, &second)
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn Link>> {
+        Ok(Box::new(SecureLink {
+            inner: self.inner.try_clone()?,
+            sending: self.sending.clone(),
+            receiving: self.receiving.clone(),
+            counters: Arc::clone(&self.counters),
+        }))
+    }
+
+    fn close(&mut self) {
+        self.inner.close();
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn transport(&self) -> &'static str {
+        self.inner.transport()
+    }
+}
+
+impl SecureLink {
+    fn decrypt(&self, first: &[u8; BUFFER_SIZE], second: &[u8; BUFFER_SIZE]) -> io::Result<[u8; BUFFER_SIZE]> {
+        let mut ciphertext = first.to_vec();
+        ciphertext.extend_from_slice(second);
+
+        let mut counters = self.counters.lock().unwrap();
+        let plaintext = self
+            .receiving
+            .decrypt(&nonce(counters.received), &ciphertext[..])
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "a message was tampered with"))?;
+        counters.received += 1;
+
+        let mut message = [0; BUFFER_SIZE];
+        message.copy_from_slice(&plaintext);
+        Ok(message)
+    }
+}
+
+/// The nonce for the `count`th message in one direction
+fn nonce(count: u64) -> Nonce {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(&count.to_be_bytes());
+    *Nonce::from_slice(&nonce)
+}
+
+/// Stretch the passphrase into a key for this match only
+fn passphrase_key(passphrase: &str, client: &[u8; NONCE_SIZE], server: &[u8; NONCE_SIZE]) -> Vec<u8> {
+    let mut salt = b"snakes passphrase ".to_vec();
+    salt.extend_from_slice(client);
+    salt.extend_from_slice(server);
+
+    let mut key = vec![0; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), &salt, KEY_ROUNDS, &mut key);
+    key
+}
+
+fn proof(key: &[u8], label: &[u8], client: &[u8; NONCE_SIZE], server: &[u8; NONCE_SIZE]) -> [u8; 32] {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(label);
+    mac.update(client);
+    mac.update(server);
+    mac.finalize().into_bytes().into()
+}
+
+fn check_proof(
+    key: &[u8],
+    label: &[u8],
+    client: &[u8; NONCE_SIZE],
+    server: &[u8; NONCE_SIZE],
+    claimed: &[u8; 32],
+) -> Result<(), hmac::digest::MacError> {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(label);
+    mac.update(client);
+    mac.update(server);
+    mac.verify_slice(claimed)
+}
+
+fn send_proof(link: &mut dyn Link, proof: &[u8; 32]) -> io::Result<()> {
+    for half in proof.chunks_exact(BUFFER_SIZE) {
+        let mut part = [0; BUFFER_SIZE];
+        part.copy_from_slice(half);
+        link.send(&part)?;
+    }
+    Ok(())
+}
+
+fn receive_proof(link: &mut dyn Link) -> io::Result<[u8; 32]> {
+    let mut proof = [0; 32];
+    for half in proof.chunks_exact_mut(BUFFER_SIZE) {
+        let part = link
+            .receive_within(HANDSHAKE_TIMEOUT)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "the other side stopped answering"))?;
+        half.copy_from_slice(&part);
+    }
+    Ok(proof)
+}
+
+fn random_nonce() -> io::Result<[u8; NONCE_SIZE]> {
+    let mut nonce = [0; NONCE_SIZE];
+    getrandom::getrandom(&mut nonce).map_err(|e| io::Error::other(e.to_string()))?;
+    Ok(nonce)
+}
+
+fn nonce_message(nonce: &[u8; NONCE_SIZE]) -> [u8; BUFFER_SIZE] {
+    let mut message = [0; BUFFER_SIZE];
+    message[0] = AUTH_MARKER;
+    message[1..1 + NONCE_SIZE].copy_from_slice(nonce);
+    concat::write_kind(&mut message, KIND_AUTH)
+}
+
+fn is_nonce_message(message: &[u8; BUFFER_SIZE]) -> bool {
+    message[0] == AUTH_MARKER && concat::read_kind(message) == KIND_AUTH
+}
+
+fn read_nonce(message: &[u8; BUFFER_SIZE]) -> [u8; NONCE_SIZE] {
+    let mut nonce = [0; NONCE_SIZE];
+    nonce.copy_from_slice(&message[1..1 + NONCE_SIZE]);
+    nonce
+}
+
+pub fn refused(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+    use std::thread;
+
+    /// One end of a link that only lives in memory
+    #[derive(Clone)]
+    struct Pipe {
+        outbox: Sender<[u8; BUFFER_SIZE]>,
+        inbox: Arc<Mutex<Receiver<[u8; BUFFER_SIZE]>>>,
+    }
+
+    /// Two ends of the same link
+    fn pair() -> (Pipe, Pipe) {
+        let (to_a, a_inbox) = mpsc::channel();
+        let (to_b, b_inbox) = mpsc::channel();
+        let a = Pipe { outbox: to_b, inbox: Arc::new(Mutex::new(a_inbox)) };
+        let b = Pipe { outbox: to_a, inbox: Arc::new(Mutex::new(b_inbox)) };
+        (a, b)
+    }
+
+    impl Link for Pipe {
+        fn send(&mut self, message: &[u8; BUFFER_SIZE]) -> io::Result<()> {
+            self.outbox.send(*message).map_err(|_| io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn receive_within(&mut self, timeout: Duration) -> io::Result<Option<[u8; BUFFER_SIZE]>> {
+            match self.inbox.lock().unwrap().recv_timeout(timeout) {
+                Ok(message) => Ok(Some(message)),
+                Err(RecvTimeoutError::Timeout) => Ok(None),
+                Err(RecvTimeoutError::Disconnected) => Err(io::ErrorKind::UnexpectedEof.into()),
+            }
+        }
+
+        fn receive(&mut self) -> io::Result<[u8; BUFFER_SIZE]> {
+            self.inbox.lock().unwrap().recv().map_err(|_| io::ErrorKind::UnexpectedEof.into())
+        }
+
+        fn try_clone(&self) -> io::Result<Box<dyn Link>> {
+            Ok(Box::new(self.clone()))
+        }
+
+        fn peer_addr(&self) -> Option<SocketAddr> {
+            None
+        }
+
+        fn transport(&self) -> &'static str {
+            "memory"
+        }
+    }
+
+    /// Shake hands over `pair`, with each side's passphrase
+    fn handshake(
+        (client, server): (Pipe, Pipe),
+        client_passphrase: &str,
+        server_passphrase: &'static str,
+    ) -> (io::Result<SecureLink>, io::Result<SecureLink>) {
+        let server = thread::spawn(move || server_handshake(Box::new(server), server_passphrase));
+        let client = client_handshake(Box::new(client), client_passphrase);
+        (client, server.join().unwrap())
+    }
+
+    fn message(text: &[u8]) -> [u8; BUFFER_SIZE] {
+        let mut message = [0; BUFFER_SIZE];
+        message[..text.len()].copy_from_slice(text);
+        message
+    }
+
+    #[test]
+    fn the_same_passphrase_gets_a_message_across() {
+        let (client, server) = handshake(pair(), "hunter2", "hunter2");
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+
+        client.send(&message(b"hello server")).unwrap();
+        assert_eq!(server.receive().unwrap(), message(b"hello server"));
+        server.send(&message(b"hello client")).unwrap();
+        assert_eq!(client.receive().unwrap(), message(b"hello client"));
+    }
+
+    #[test]
+    fn a_wrong_passphrase_is_refused_on_both_sides() {
+        let (client, server) = handshake(pair(), "hunter2", "hunter3");
+        assert_eq!(client.err().map(|e| e.kind()), Some(io::ErrorKind::PermissionDenied));
+        assert_eq!(server.err().map(|e| e.kind()), Some(io::ErrorKind::PermissionDenied));
+    }
+
+    #[test]
+    fn tampered_and_replayed_messages_are_rejected() {
+        let (client_end, server_end) = pair();
+        // What the client sends, before the server gets to read it
+        let mut wire = server_end.clone();
+        let mut forward = client_end.clone();
+        let (client, server) = handshake((client_end, server_end), "hunter2", "hunter2");
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+
+        client.send(&message(b"first")).unwrap();
+        let first = [wire.receive().unwrap(), wire.receive().unwrap()];
+        for part in first.iter() {
+            forward.send(part).unwrap();
+        }
+        assert_eq!(server.receive().unwrap(), message(b"first"));
+
+        // The same message again
+        for part in first.iter() {
+            forward.send(part).unwrap();
+        }
+        assert_eq!(server.receive().unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        client.send(&message(b"second")).unwrap();
+        let mut second = [wire.receive().unwrap(), wire.receive().unwrap()];
+        second[0][3] ^= 1;
+        for part in second.iter() {
+            forward.send(part).unwrap();
+        }
+        assert_eq!(server.receive().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn a_server_without_a_passphrase_times_out() {
+        let (client_end, server_end) = pair();
+        // The server hears the client out, but never answers
+        let started = std::time::Instant::now();
+        let result = client_handshake(Box::new(client_end), "hunter2");
+        drop(server_end);
+
+        assert_eq!(result.err().map(|e| e.kind()), Some(io::ErrorKind::PermissionDenied));
+        assert!(started.elapsed() >= HANDSHAKE_TIMEOUT);
+    }
+
+    #[test]
+    fn a_client_without_a_passphrase_is_refused() {
+        let (mut client_end, server_end) = pair();
+        let mut ready = [0; BUFFER_SIZE];
+        client_end.send(&concat::write_ready(&mut ready)).unwrap();
+
+        let result = server_handshake(Box::new(server_end), "hunter2");
+        assert_eq!(result.err().map(|e| e.kind()), Some(io::ErrorKind::PermissionDenied));
+    }
+}
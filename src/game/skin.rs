@@ -0,0 +1,195 @@
+//! How a snake looks on the board. Every player picks a skin for their own
+//! snake, which travels to the other player along with their name and
+//! colour. Whatever the skin, a whole snake is built into one mesh and drawn
+//! at once.
+
+use druid::Data;
+use ggez::graphics::{self, DrawMode, MeshBuilder};
+use ggez::mint::Point2;
+use ggez::{Context, GameResult};
+
+use super::profile::Colour;
+use super::{Direction, GRID_CELL_SIZE};
+
+/// How closely the circles follow a true circle, in pixels
+const TOLERANCE: f32 = 0.5;
+/// How many segments at the end of the tail get thinner
+const TAPERED_SEGMENTS: usize = 3;
+/// How thick the very end of the tail is, next to the rest of the snake
+const TAIL_THICKNESS: f32 = 0.45;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Data)]
+pub enum Skin {
+    /// A square on every cell, the way snakes always looked
+    Blocks,
+    /// A round blob on every cell
+    Rounded,
+    /// One smooth body with no gaps between the cells
+    #[default]
+    Connected,
+}
+
+impl Skin {
+    pub const ALL: [Skin; 3] = [Skin::Blocks, Skin::Rounded, Skin::Connected];
+
+    pub fn from_name(name: &str) -> Option<Skin> {
+        Self::ALL.iter().copied().find(|skin| skin.name().eq_ignore_ascii_case(name))
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Skin::Blocks    => "blocks",
+            Skin::Rounded   => "rounded",
+            Skin::Connected => "connected",
+        }
+    }
+
+    /// The skin that comes after this one in the launcher
+    pub fn cycle(self) -> Skin {
+        let index = Self::ALL.iter().position(|skin| *skin == self).unwrap_or(0);
+        Self::ALL[(index + 1) % Self::ALL.len()]
+    }
+
+    /// How the skin goes over the network
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Skin::Blocks    => 0,
+            Skin::Rounded   => 1,
+            Skin::Connected => 2,
+        }
+    }
+
+    /// A skin we don't know yet is drawn as blocks, which every snake can
+    /// look like
+    pub fn from_byte(byte: u8) -> Skin {
+        match byte {
+            1 => Skin::Rounded,
+            2 => Skin::Connected,
+            _ => Skin::Blocks,
+        }
+    }
+
+    /// Draw a snake whose segments are on the cells at `cells`, head first,
+    /// heading `dir`. The cells don't have to be whole numbers, so that a
+    /// snake can be drawn on its way from one cell to the next.
+    pub fn draw(self, ctx: &mut Context, cells: &[(f32, f32)], dir: Direction, colour: Colour) -> GameResult<()> {
+        if cells.is_empty() {
+            return Ok(());
+        }
+        let mut mesh = MeshBuilder::new();
+        match self {
+            Skin::Blocks => blocks(&mut mesh, cells, colour),
+            Skin::Rounded => {
+                round_segments(&mut mesh, cells, colour);
+                eyes(&mut mesh, cells[0], dir);
+            }
+            Skin::Connected => {
+                joints(&mut mesh, cells, colour)?;
+                round_segments(&mut mesh, cells, colour);
+                eyes(&mut mesh, cells[0], dir);
+            }
+        }
+        let mesh = mesh.build(ctx)?;
+        graphics::draw(ctx, &mesh, (Point2 { x: 0.0, y: 0.0 },))
+    }
+}
+
+/// The middle of the cell at `cell`, in pixels
+fn centre((x, y): (f32, f32)) -> Point2<f32> {
+    Point2 {
+        x: (x + 0.5) * GRID_CELL_SIZE.0 as f32,
+        y: (y + 0.5) * GRID_CELL_SIZE.1 as f32,
+    }
+}
+
+/// How wide the segment `i` of a snake with `len` segments is, as a part
+/// of a cell. The last few get thinner towards the end of the tail, but the
+/// head never does, however short the snake is.
+fn thickness(i: usize, len: usize) -> f32 {
+    let tapered = TAPERED_SEGMENTS.min(len.saturating_sub(1));
+    let from_end = len - 1 - i;
+    if len <= 2 || i == 0 || from_end >= tapered {
+        return 1.0;
+    }
+    let step = (1.0 - TAIL_THICKNESS) / tapered as f32;
+    TAIL_THICKNESS + step * from_end as f32
+}
+
+/// Whether two segments are next to each other on the board, rather than
+/// on opposite edges after the snake wrapped around
+fn neighbours(a: (f32, f32), b: (f32, f32)) -> bool {
+    (a.0 - b.0).abs() + (a.1 - b.1).abs() <= 1.01
+}
+
+fn blocks(mesh: &mut MeshBuilder, cells: &[(f32, f32)], colour: Colour) {
+    // Back to front, so that the head ends up on top
+    for (i, &(x, y)) in cells.iter().enumerate().rev() {
+        let rect = graphics::Rect::new(
+            x * GRID_CELL_SIZE.0 as f32,
+            y * GRID_CELL_SIZE.1 as f32,
+            GRID_CELL_SIZE.0 as f32,
+            GRID_CELL_SIZE.1 as f32,
+        );
+        let color = if i == 0 { colour.head() } else { colour.body() };
+        mesh.rectangle(DrawMode::fill(), rect, color);
+    }
+}
+
+fn round_segments(mesh: &mut MeshBuilder, cells: &[(f32, f32)], colour: Colour) {
+    let radius = GRID_CELL_SIZE.0.min(GRID_CELL_SIZE.1) as f32 / 2.0;
+    for (i, &cell) in cells.iter().enumerate().rev() {
+        let color = if i == 0 { colour.head() } else { colour.body() };
+        mesh.circle(DrawMode::fill(), centre(cell), radius * thickness(i, cells.len()), TOLERANCE, color);
+    }
+}
+
+/// Fill the gaps between the round segments, so the snake is all one piece
+fn joints(mesh: &mut MeshBuilder, cells: &[(f32, f32)], colour: Colour) -> GameResult<()> {
+    let half_width = GRID_CELL_SIZE.0.min(GRID_CELL_SIZE.1) as f32 / 2.0;
+    for (i, pair) in cells.windows(2).enumerate() {
+        if !neighbours(pair[0], pair[1]) {
+            continue;
+        }
+        let (a, b) = (centre(pair[0]), centre(pair[1]));
+        let (dx, dy) = (b.x - a.x, b.y - a.y);
+        let length = (dx * dx + dy * dy).sqrt();
+        if length == 0.0 {
+            continue;
+        }
+        // Across the snake, as wide as the thinner of the two segments
+        let width = half_width * thickness(i + 1, cells.len());
+        let (across_x, across_y) = (-dy / length * width, dx / length * width);
+        let corners = [
+            Point2 { x: a.x + across_x, y: a.y + across_y },
+            Point2 { x: b.x + across_x, y: b.y + across_y },
+            Point2 { x: b.x - across_x, y: b.y - across_y },
+            Point2 { x: a.x - across_x, y: a.y - across_y },
+        ];
+        mesh.polygon(DrawMode::fill(), &corners, colour.body())?;
+    }
+    Ok(())
+}
+
+/// Two eyes on the head at `head`, looking the way the snake is going
+fn eyes(mesh: &mut MeshBuilder, head: (f32, f32), dir: Direction) {
+    let size = GRID_CELL_SIZE.0.min(GRID_CELL_SIZE.1) as f32;
+    let (ahead_x, ahead_y) = match dir {
+        Direction::Up    => (0.0, -1.0),
+        Direction::Down  => (0.0, 1.0),
+        Direction::Left  => (-1.0, 0.0),
+        Direction::Right => (1.0, 0.0),
+    };
+    let middle = centre(head);
+    for side in [-1.0, 1.0].iter() {
+        let eye = Point2 {
+            x: middle.x + ahead_x * size * 0.15 - ahead_y * side * size * 0.22,
+            y: middle.y + ahead_y * size * 0.15 + ahead_x * side * size * 0.22,
+        };
+        let pupil = Point2 {
+            x: eye.x + ahead_x * size * 0.05,
+            y: eye.y + ahead_y * size * 0.05,
+        };
+        mesh.circle(DrawMode::fill(), eye, size * 0.14, TOLERANCE, graphics::WHITE);
+        mesh.circle(DrawMode::fill(), pupil, size * 0.07, TOLERANCE, graphics::BLACK);
+    }
+}
@@ -0,0 +1,436 @@
+//! Everything about a match at the end of one tick: where every segment of
+//! both snakes is and where they're heading, the food, the RNG and the tick.
+//! Both sides of a networked match hash one every now and then to check
+//! that they still agree about the board, and when they don't, the server
+//! sends the client its whole snapshot to start over from. Snapshots also
+//! go to disk, for saved games and for looking at what went wrong.
+//!
+//! There are two ways to write one down. The binary one is what goes over
+//! the network and into the hash, and its numbers are big-endian:
+//!
+//! | bytes | what                                                  |
+//! |-------|-------------------------------------------------------|
+//! | 4     | tick, as u32                                          |
+//! | 16    | RNG state, as two u64                                 |
+//! | 4     | food x and y, as i16                                  |
+//! | 2     | 1 if the game is over, and 1 if the first snake is solo |
+//!
+//! and then for each snake its direction, last direction and next direction
+//! (4 for none), what it ate (0 nothing, 1 food, 2 itself), how many segments
+//! it has as u16, and where each of them is, head first, as two i16. Scores
+//! aren't written down, since they follow from how long the snakes are.
+//!
+//! The JSON one is for people to read, and looks like
+//!
+//! ```text
+//! {
+//!   "tick": 120,
+//!   "rng": [12345678901234, 1442695040888963407],
+//!   "food": [3, 7],
+//!   "gameover": false,
+//!   "solo": false,
+//!   "snakes": [
+//!     {
+//!       "segments": [[9, 5], [8, 5], [7, 5]],
+//!       "dir": "right",
+//!       "last_update_dir": "right",
+//!       "next_dir": null,
+//!       "ate": null,
+//!       "score": 1
+//!     },
+//!     ...
+//!   ]
+//! }
+//! ```
+//!
+//! where `ate` is `null`, `"food"` or `"itself"`, and the score is only there
+//! for reading, so it's ignored when the snapshot is read back.
+
+use oorandom::Rand32;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use std::collections::LinkedList;
+use std::convert::{TryFrom, TryInto};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use crate::config::app_dir;
+
+use super::json::{direction_from_name, direction_name, position_to_json};
+use super::messages::ProtocolError;
+use super::world::World;
+use super::{Ate, Direction, GridPosition, Segment, Snake};
+
+/// Where desync dumps go, inside our directory
+const DUMP_DIR: &str = "desyncs";
+
+#[derive(Clone)]
+pub struct Snapshot {
+    pub tick: u128,
+    pub world: World,
+}
+
+impl Snapshot {
+    pub fn new(world: &World, tick: u128) -> Self {
+        Snapshot { tick, world: world.clone() }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let world = &self.world;
+        let mut bytes = Vec::new();
+
+        bytes.extend_from_slice(&(self.tick as u32).to_be_bytes());
+        let (state, inc) = world.rng.state();
+        bytes.extend_from_slice(&state.to_be_bytes());
+        bytes.extend_from_slice(&inc.to_be_bytes());
+        bytes.extend_from_slice(&world.food.pos.to_bytes());
+        bytes.push(world.gameover as u8);
+        bytes.push(world.solo as u8);
+
+        for snake in [&world.player1, &world.player2].iter() {
+            bytes.push(snake.dir.to_bytes()[0]);
+            bytes.push(snake.last_update_dir.to_bytes()[0]);
+            bytes.push(snake.next_dir.map_or(4, |d| d.to_bytes()[0]));
+            bytes.push(match snake.ate {
+                None              => 0,
+                Some(Ate::Food)   => 1,
+                Some(Ate::Itself) => 2,
+            });
+            let segments = snake.segments();
+            bytes.extend_from_slice(&(segments.len() as u16).to_be_bytes());
+            for pos in segments {
+                bytes.extend_from_slice(&pos.to_bytes());
+            }
+        }
+
+        bytes
+    }
+
+    /// Read a snapshot the server sent, which had better make sense
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let mut reader = Reader { bytes };
+
+        let tick = u32::from_be_bytes(reader.take()?) as u128;
+        let state = u64::from_be_bytes(reader.take()?);
+        let inc = u64::from_be_bytes(reader.take()?);
+        let food = GridPosition::from_bytes(&reader.take()?)?;
+        let gameover = reader.flag()?;
+        let solo = reader.flag()?;
+
+        let mut world = World::new(Rand32::from_state((state, inc)), food, solo);
+        world.gameover = gameover;
+        world.player1 = reader.snake()?;
+        world.player2 = reader.snake()?;
+
+        if !reader.bytes.is_empty() {
+            return Err(ProtocolError::BadSnapshot);
+        }
+        Ok(Snapshot { tick, world })
+    }
+
+    pub fn to_json(&self) -> Value {
+        let world = &self.world;
+        let (state, inc) = world.rng.state();
+        let snakes: Vec<Value> = [&world.player1, &world.player2]
+            .iter()
+            .map(|snake| {
+                let segments: Vec<Value> = snake.segments().into_iter().map(position_to_json).collect();
+                json!({
+                    "segments": segments,
+                    "dir": direction_name(snake.dir),
+                    "last_update_dir": direction_name(snake.last_update_dir),
+                    "next_dir": snake.next_dir.map(direction_name),
+                    "ate": match snake.ate {
+                        None              => None,
+                        Some(Ate::Food)   => Some("food"),
+                        Some(Ate::Itself) => Some("itself"),
+                    },
+                    "score": snake.score(),
+                })
+            })
+            .collect();
+
+        json!({
+            "tick": self.tick as u64,
+            "rng": [state, inc],
+            "food": position_to_json(world.food.pos),
+            "gameover": world.gameover,
+            "solo": world.solo,
+            "snakes": snakes,
+        })
+    }
+
+    /// Read a snapshot someone wrote down as JSON, which might have been
+    /// edited since
+    pub fn from_json(value: &Value) -> Result<Self, ProtocolError> {
+        let bad = || ProtocolError::BadSnapshot;
+        let number = |key: &str| value.get(key).and_then(Value::as_u64).ok_or_else(bad);
+        let flag = |key: &str| value.get(key).and_then(Value::as_bool).ok_or_else(bad);
+
+        let tick = number("tick")? as u128;
+        let rng = match value.get("rng").and_then(Value::as_array).map(Vec::as_slice) {
+            Some([state, inc]) => (state.as_u64().ok_or_else(bad)?, inc.as_u64().ok_or_else(bad)?),
+            _ => return Err(bad()),
+        };
+        let food = position_from_json(value.get("food").ok_or_else(bad)?)?;
+
+        let mut world = World::new(Rand32::from_state(rng), food, flag("solo")?);
+        world.gameover = flag("gameover")?;
+        match value.get("snakes").and_then(Value::as_array).map(Vec::as_slice) {
+            Some([one, two]) => {
+                world.player1 = snake_from_json(one)?;
+                world.player2 = snake_from_json(two)?;
+            }
+            _ => return Err(bad()),
+        }
+
+        Ok(Snapshot { tick, world })
+    }
+
+    /// A hash of the whole snapshot, which is the same on both sides if
+    /// they agree about everything
+    pub fn checksum(&self) -> u64 {
+        let hash = Sha256::digest(self.to_bytes());
+        u64::from_be_bytes(hash[..8].try_into().expect("SHA-256 is longer than 8 bytes"))
+    }
+
+    /// Write the snapshot as JSON to a file called `name` in the dump
+    /// directory, so we can look at what went wrong later
+    pub fn dump(&self, name: &str) -> io::Result<PathBuf> {
+        let dir = app_dir().join(DUMP_DIR);
+        fs::create_dir_all(&dir)?;
+
+        let path = dir.join(format!("{}.json", name));
+        let contents = serde_json::to_string_pretty(&self.to_json()).map_err(io::Error::other)?;
+        fs::write(&path, contents)?;
+        Ok(path)
+    }
+}
+
+/// Takes bytes off the front of a snapshot
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], ProtocolError> {
+        if self.bytes.len() < N {
+            return Err(ProtocolError::BadSnapshot);
+        }
+        let (taken, rest) = self.bytes.split_at(N);
+        self.bytes = rest;
+        Ok(taken.try_into().expect("we took exactly N bytes"))
+    }
+
+    fn flag(&mut self) -> Result<bool, ProtocolError> {
+        match self.take::<1>()? {
+            [0] => Ok(false),
+            [1] => Ok(true),
+            [b] => Err(ProtocolError::BadFlag(b)),
+        }
+    }
+
+    fn snake(&mut self) -> Result<Snake, ProtocolError> {
+        let dir = Direction::from_bytes(&self.take()?)?;
+        let last_update_dir = Direction::from_bytes(&self.take()?)?;
+        let next_dir = match self.take::<1>()? {
+            [4] => None,
+            b => Some(Direction::from_bytes(&b)?),
+        };
+        let ate = match self.take::<1>()? {
+            [0] => None,
+            [1] => Some(Ate::Food),
+            [2] => Some(Ate::Itself),
+            [b] => return Err(ProtocolError::BadFlag(b)),
+        };
+
+        let len = u16::from_be_bytes(self.take()?);
+        let mut segments = Vec::new();
+        for _ in 0..len {
+            segments.push(GridPosition::from_bytes(&self.take()?)?);
+        }
+
+        build_snake(segments, dir, last_update_dir, next_dir, ate)
+    }
+}
+
+/// Put a snake back together from what a snapshot says about it. Every
+/// snake has a head and at least one body segment, so a snapshot with less
+/// than that doesn't add up.
+fn build_snake(
+    segments: Vec<GridPosition>,
+    dir: Direction,
+    last_update_dir: Direction,
+    next_dir: Option<Direction>,
+    ate: Option<Ate>,
+) -> Result<Snake, ProtocolError> {
+    if segments.len() < 2 {
+        return Err(ProtocolError::BadSnapshot);
+    }
+    let mut segments = segments.into_iter();
+    let head = segments.next().ok_or(ProtocolError::BadSnapshot)?;
+
+    let mut snake = Snake::new(head);
+    snake.body = segments.map(Segment::new).collect::<LinkedList<_>>();
+    snake.dir = dir;
+    snake.last_update_dir = last_update_dir;
+    snake.next_dir = next_dir;
+    snake.ate = ate;
+    Ok(snake)
+}
+
+fn position_from_json(value: &Value) -> Result<GridPosition, ProtocolError> {
+    let coordinate = |value: &Value| {
+        value
+            .as_i64()
+            .and_then(|c| i16::try_from(c).ok())
+            .ok_or(ProtocolError::BadSnapshot)
+    };
+    match value.as_array().map(Vec::as_slice) {
+        Some([x, y]) => GridPosition::on_board(coordinate(x)?, coordinate(y)?),
+        _ => Err(ProtocolError::BadSnapshot),
+    }
+}
+
+fn snake_from_json(value: &Value) -> Result<Snake, ProtocolError> {
+    let bad = || ProtocolError::BadSnapshot;
+    let direction = |key: &str| value.get(key).and_then(Value::as_str).and_then(direction_from_name);
+
+    let segments = value
+        .get("segments")
+        .and_then(Value::as_array)
+        .ok_or_else(bad)?
+        .iter()
+        .map(position_from_json)
+        .collect::<Result<Vec<_>, _>>()?;
+    let next_dir = match value.get("next_dir") {
+        None | Some(Value::Null) => None,
+        Some(_) => Some(direction("next_dir").ok_or_else(bad)?),
+    };
+    let ate = match value.get("ate").map(|ate| (ate.is_null(), ate.as_str())) {
+        None | Some((true, _)) => None,
+        Some((_, Some("food")))   => Some(Ate::Food),
+        Some((_, Some("itself"))) => Some(Ate::Itself),
+        Some(_) => return Err(bad()),
+    };
+
+    build_snake(
+        segments,
+        direction("dir").ok_or_else(bad)?,
+        direction("last_update_dir").ok_or_else(bad)?,
+        next_dir,
+        ate,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Where the first snake starts in a snapshot's bytes
+    const FIRST_SNAKE: usize = 26;
+
+    fn snapshot() -> Snapshot {
+        let mut world = World::new(Rand32::new(7), GridPosition::new(3, 7), false);
+        world.player1.next_dir = Some(Direction::Up);
+        world.player2.ate = Some(Ate::Food);
+        Snapshot::new(&world, 120)
+    }
+
+    #[test]
+    fn snapshots_come_back_the_same() {
+        let bytes = snapshot().to_bytes();
+        let read = Snapshot::from_bytes(&bytes).unwrap();
+
+        assert_eq!(read.tick, 120);
+        assert_eq!(read.to_bytes(), bytes);
+        assert_eq!(read.checksum(), snapshot().checksum());
+    }
+
+    #[test]
+    fn the_checksum_changes_with_the_board() {
+        let mut moved = snapshot();
+        moved.world.food.pos = GridPosition::new(4, 7);
+        assert_ne!(moved.checksum(), snapshot().checksum());
+    }
+
+    #[test]
+    fn snapshots_cut_short_or_too_long_are_rejected() {
+        let bytes = snapshot().to_bytes();
+        for len in [0, FIRST_SNAKE, bytes.len() - 1].iter() {
+            assert_eq!(Snapshot::from_bytes(&bytes[..*len]).err(), Some(ProtocolError::BadSnapshot));
+        }
+
+        let mut longer = bytes;
+        longer.push(0);
+        assert_eq!(Snapshot::from_bytes(&longer).err(), Some(ProtocolError::BadSnapshot));
+    }
+
+    #[test]
+    fn snakes_with_less_than_two_segments_are_rejected() {
+        let mut bytes = snapshot().to_bytes();
+        let len = FIRST_SNAKE + 4;
+        let segments = u16::from_be_bytes([bytes[len], bytes[len + 1]]) as usize;
+        // Only keep the head
+        bytes[len..len + 2].copy_from_slice(&1u16.to_be_bytes());
+        bytes.drain(len + 2 + 4..len + 2 + 4 * segments);
+
+        assert_eq!(Snapshot::from_bytes(&bytes).err(), Some(ProtocolError::BadSnapshot));
+    }
+
+    #[test]
+    fn snapshots_with_bad_bytes_are_rejected() {
+        let mut bytes = snapshot().to_bytes();
+        bytes[24] = 2;
+        assert_eq!(Snapshot::from_bytes(&bytes).err(), Some(ProtocolError::BadFlag(2)));
+
+        let mut bytes = snapshot().to_bytes();
+        bytes[FIRST_SNAKE] = 9;
+        assert_eq!(Snapshot::from_bytes(&bytes).err(), Some(ProtocolError::BadDirection(9)));
+
+        let mut bytes = snapshot().to_bytes();
+        bytes[20..24].copy_from_slice(&GridPosition::new(0, -3).to_bytes());
+        assert_eq!(Snapshot::from_bytes(&bytes).err(), Some(ProtocolError::OffTheBoard(0, -3)));
+    }
+
+    #[test]
+    fn snapshots_come_back_the_same_from_json() {
+        let read = Snapshot::from_json(&snapshot().to_json()).unwrap();
+        assert_eq!(read.tick, 120);
+        assert_eq!(read.to_bytes(), snapshot().to_bytes());
+    }
+
+    #[test]
+    fn scores_in_json_are_ignored() {
+        let mut value = snapshot().to_json();
+        value["snakes"][0]["score"] = json!(99);
+        assert_eq!(Snapshot::from_json(&value).unwrap().to_bytes(), snapshot().to_bytes());
+    }
+
+    #[test]
+    fn json_snapshots_that_dont_add_up_are_rejected() {
+        let broken: [fn(&mut Value); 7] = [
+            |value| value["tick"] = json!(-1),
+            |value| value["rng"] = json!([1]),
+            |value| value["snakes"] = json!([]),
+            |value| value["snakes"][0]["segments"] = json!([[9, 5]]),
+            |value| value["snakes"][1]["dir"] = json!("sideways"),
+            |value| value["snakes"][1]["ate"] = json!("bugs"),
+            |value| value["snakes"][0]["next_dir"] = json!(3),
+        ];
+        for change in broken.iter() {
+            let mut value = snapshot().to_json();
+            change(&mut value);
+            assert_eq!(Snapshot::from_json(&value).err(), Some(ProtocolError::BadSnapshot));
+        }
+    }
+
+    #[test]
+    fn json_positions_off_the_board_are_rejected() {
+        let mut value = snapshot().to_json();
+        value["food"] = json!([3, 70]);
+        assert_eq!(Snapshot::from_json(&value).err(), Some(ProtocolError::OffTheBoard(3, 70)));
+    }
+}
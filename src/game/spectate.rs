@@ -0,0 +1,125 @@
+//! People watching a match on the server without playing in it, like a web
+//! page that shows the board. They connect to the server's port the same way
+//! a WebSocket client does, but ask for `/watch`, and if the match has a
+//! passphrase, they have to know it as well. They can come in before the
+//! match starts or while it's on.
+//!
+//! A spectator never says anything after that. It first gets a kind 7
+//! message for each player, the same as the one the server sends a client
+//! about its player, but with the player's number, 1 or 2, in the first
+//! byte. After every tick it gets the whole board, in the same kind 6
+//! messages a client gets when its board drifted from the server's, so it
+//! doesn't have to play the match along to show it.
+
+use log::info;
+
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use super::profile::Profile;
+use super::{concat, messages, Link, BUFFER_SIZE};
+
+/// What a spectator asks for in its WebSocket handshake
+pub const WATCH_PATH: &str = "/watch";
+
+/// How many boards a spectator can fall behind before it stops watching
+const SPECTATOR_QUEUE: usize = 8;
+
+/// Everyone watching a match. Whoever lets them in and the match itself
+/// share it between their threads.
+#[derive(Clone, Default)]
+pub struct Spectators {
+    audience: Arc<Mutex<Audience>>,
+}
+
+/// Messages that go out to one spectator together
+type Batch = Vec<[u8; BUFFER_SIZE]>;
+
+#[derive(Default)]
+struct Audience {
+    /// What's still to be sent to each spectator. Every spectator has a
+    /// thread of its own that sends it, so a slow one never holds up the
+    /// match.
+    queues: Vec<SyncSender<Batch>>,
+    /// Who plays which snake, which every spectator gets first
+    players: Batch,
+}
+
+impl Spectators {
+    /// Let another spectator in. Anyone who comes in before the match
+    /// starts is told who's playing once we know.
+    pub fn add(&self, link: Box<dyn Link>) {
+        let queue = spawn_sender(link);
+        let mut audience = self.audience.lock().unwrap();
+        if audience.players.is_empty() || offer(&queue, audience.players.clone()) {
+            audience.queues.push(queue);
+        }
+    }
+
+    /// Hang up on all of them, since the match is over. Each of them still
+    /// gets what was already on its way.
+    pub fn close(&self) {
+        self.audience.lock().unwrap().queues.clear();
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.audience.lock().unwrap().queues.is_empty()
+    }
+
+    /// Tell everyone who plays which snake, now and whenever they come in
+    pub(super) fn introduce(&self, profiles: &[Profile; 2]) {
+        let players: Batch = profiles
+            .iter()
+            .enumerate()
+            .map(|(i, profile)| {
+                let mut buffer = [0; BUFFER_SIZE];
+                buffer[0] = i as u8 + 1;
+                buffer = concat::write_profile(&mut buffer, profile);
+                concat::write_kind(&mut buffer, messages::KIND_PROFILE)
+            })
+            .collect();
+        let mut audience = self.audience.lock().unwrap();
+        audience.queues.retain(|queue| offer(queue, players.clone()));
+        audience.players = players;
+    }
+
+    /// Show everyone the board, as a snapshot in `messages`. Anyone who
+    /// can't keep up stops watching.
+    pub(super) fn show(&self, messages: &[[u8; BUFFER_SIZE]]) {
+        self.audience
+            .lock()
+            .unwrap()
+            .queues
+            .retain(|queue| offer(queue, messages.to_vec()));
+    }
+}
+
+/// Start the thread that sends everything queued for one spectator. It hangs
+/// up once the spectator is dropped from the audience or can't be sent to
+/// anymore.
+fn spawn_sender(mut link: Box<dyn Link>) -> SyncSender<Batch> {
+    let (queue, batches) = mpsc::sync_channel::<Batch>(SPECTATOR_QUEUE);
+    thread::spawn(move || {
+        for batch in batches {
+            if let Err(e) = batch.iter().try_for_each(|message| link.send(message)) {
+                info!("A spectator stopped watching: {}", e);
+                break;
+            }
+        }
+        link.close();
+    });
+    queue
+}
+
+/// Queue `batch` for one spectator, and whether it's still watching
+fn offer(queue: &SyncSender<Batch>, batch: Batch) -> bool {
+    match queue.try_send(batch) {
+        Ok(()) => true,
+        Err(TrySendError::Full(_)) => {
+            info!("A spectator fell too far behind to keep watching");
+            false
+        }
+        Err(TrySendError::Disconnected(_)) => false,
+    }
+}
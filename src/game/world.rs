@@ -0,0 +1,135 @@
+use oorandom::Rand32;
+
+use ggez::{Context, GameResult};
+
+use super::profile::Profile;
+use super::{Ate, Direction, Food, GridPosition, Pilot, Player, Snake, GRID_SIZE};
+
+/// The board and everything on it. This is the part of the game that doesn't
+/// care about windows, keyboards or connections, so that a match can also be
+/// played headless.
+#[derive(Clone)]
+pub struct World {
+    /// We first need a snake
+    pub player1: Snake,
+    pub player2: Snake,
+    /// A piece of food
+    pub food: Food,
+    /// Whether the game is over or not
+    pub gameover: bool,
+    /// Our RNG state
+    pub rng: Rand32,
+    /// Whether the first snake is on its own, like in single-player
+    pub solo: bool,
+}
+
+impl World {
+    /// Set up the board with the food at `food_pos`.
+    pub fn new(rng: Rand32, food_pos: GridPosition, solo: bool) -> Self {
+        // First we put our snake a quarter of the way accross our grid in the x axis.
+        // and half way down the y axis. This works well since we start out moving to the right
+        let mod_pos = GRID_SIZE.1 / 4;
+        let snake_pos_2 = (GRID_SIZE.0 / 4, mod_pos + GRID_SIZE.1 / 2).into();
+        let snake_pos_1 = (GRID_SIZE.0 / 4, mod_pos).into();
+
+        World {
+            player1: Snake::new(snake_pos_1),
+            player2: Snake::new(snake_pos_2),
+            food: Food::new(food_pos),
+            gameover: false,
+            rng,
+            solo,
+        }
+    }
+
+    /// Set up the board for a match where `rng` also decides where the
+    /// first piece of food goes.
+    pub fn with_rng(mut rng: Rand32, solo: bool) -> Self {
+        let food_pos = GridPosition::random(&mut rng, GRID_SIZE.0, GRID_SIZE.1);
+        Self::new(rng, food_pos, solo)
+    }
+
+    /// Check whether either snake ate anything during its last update.
+    /// Eating food moves it somewhere else, while eating itself ends the game.
+    pub fn handle_ate(&mut self) {
+        for ate in [self.player1.ate, self.player2.ate].iter() {
+            match ate {
+                Some(Ate::Food) => {
+                    let new_food_pos =
+                        GridPosition::random(&mut self.rng, GRID_SIZE.0, GRID_SIZE.1);
+                    self.food.pos = new_food_pos;
+                }
+                Some(Ate::Itself) => {
+                    self.gameover = true;
+                }
+                None => (),
+            }
+        }
+    }
+
+    /// Move every snake one step and see what they ate. This is a whole
+    /// tick of a game where all the snakes live on this machine.
+    pub fn step(&mut self) {
+        self.player1.update(&self.food);
+        if !self.solo {
+            self.player2.update(&self.food);
+        }
+        self.handle_ate();
+    }
+
+    /// Let the pilots look at the board and steer their snakes before
+    /// the snakes move. Returns where each of them asked to go, if anywhere.
+    pub fn run_pilots(&mut self, pilots: &mut [Option<Box<dyn Pilot>>; 2], tick: u128) -> [Option<Direction>; 2] {
+        let mut asked = [None, None];
+        if let Some(pilot) = pilots[0].as_mut() {
+            let other = if self.solo { None } else { Some(&self.player2) };
+            if let Some(dir) = pilot.next_direction(&self.player1, other, &self.food, tick) {
+                self.player1.steer(dir);
+                asked[0] = Some(dir);
+            }
+        }
+        if let Some(pilot) = pilots[1].as_mut() {
+            if let Some(dir) = pilot.next_direction(&self.player2, Some(&self.player1), &self.food, tick) {
+                self.player2.steer(dir);
+                asked[1] = Some(dir);
+            }
+        }
+        asked
+    }
+
+    /// Whether `player` won the match that just ended, or `None` for a draw.
+    /// Whoever ate themselves lost.
+    pub fn won(&self, player: Player) -> Option<bool> {
+        let lost = |snake: &Snake| matches!(snake.ate, Some(Ate::Itself));
+        let (me, other) = match player {
+            Player::One => (&self.player1, &self.player2),
+            Player::Two => (&self.player2, &self.player1),
+        };
+
+        match (lost(me), lost(other)) {
+            (true, false) => Some(false),
+            (false, true) => Some(true),
+            _             => None,
+        }
+    }
+
+    /// Tell the pilots how the match went
+    pub fn end_pilots(&self, pilots: &mut [Option<Box<dyn Pilot>>; 2]) {
+        let results = [self.won(Player::One), self.won(Player::Two)];
+        for (pilot, won) in pilots.iter_mut().zip(results.iter()) {
+            if let Some(pilot) = pilot {
+                pilot.game_over(*won);
+            }
+        }
+    }
+
+    /// Tell the snakes, the way their players want them to look, and the
+    /// food to draw themselves.
+    pub fn draw(&self, ctx: &mut Context, profiles: &[Profile; 2]) -> GameResult<()> {
+        self.player1.draw(ctx, &profiles[0])?;
+        if !self.solo {
+            self.player2.draw(ctx, &profiles[1])?;
+        }
+        self.food.draw(ctx)
+    }
+}
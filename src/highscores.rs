@@ -0,0 +1,109 @@
+use std::fmt::Display;
+use std::fs;
+use std::io;
+
+use super::config::app_dir;
+
+/// The file we keep the table in, inside our config directory
+const HIGH_SCORES_FILE: &str = "highscores.txt";
+/// How many scores make it into the table
+const MAX_ENTRIES: usize = 10;
+
+/// A single row of the high-score table
+#[derive(Clone, Debug, PartialEq)]
+pub struct HighScore {
+    pub score: u32,
+    pub name: String,
+    /// The day the score was set, as `YYYY-MM-DD`
+    pub date: String,
+}
+
+/// The best single-player scores, highest first. On disk, every entry is a
+/// line of tab separated `score`, `date` and `name`.
+#[derive(Clone, Debug, Default)]
+pub struct HighScores {
+    entries: Vec<HighScore>,
+}
+
+impl HighScores {
+    /// Load the table, starting with an empty one if there's no file yet.
+    pub fn load() -> Self {
+        match fs::read_to_string(app_dir().join(HIGH_SCORES_FILE)) {
+            Ok(contents) => Self::parse(&contents),
+            Err(_)       => Self::default(),
+        }
+    }
+
+    fn parse(contents: &str) -> Self {
+        let mut entries: Vec<HighScore> = contents
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.splitn(3, '\t');
+                let score = fields.next()?.parse().ok()?;
+                let date = fields.next()?.to_string();
+                let name = fields.next()?.to_string();
+
+                Some(HighScore { score, name, date })
+            })
+            .collect();
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.score));
+        entries.truncate(MAX_ENTRIES);
+
+        HighScores { entries }
+    }
+
+    /// Add a score to the table if it's good enough to make it in, dated
+    /// today. Returns whether it did.
+    pub fn add(&mut self, score: u32, name: &str) -> bool {
+        // Tabs and newlines would break our file format
+        let name: String = name
+            .chars()
+            .map(|c| if c.is_control() { ' ' } else { c })
+            .collect();
+        let name = match name.trim() {
+            "" => "Anonymous".to_string(),
+            n  => n.to_string(),
+        };
+        let date = chrono::Local::now().format("%Y-%m-%d").to_string();
+
+        // Scores that tie an existing one go below it
+        let rank = self.entries
+            .iter()
+            .position(|entry| entry.score < score)
+            .unwrap_or(self.entries.len());
+        if rank >= MAX_ENTRIES {
+            return false;
+        }
+
+        self.entries.insert(rank, HighScore { score, name, date });
+        self.entries.truncate(MAX_ENTRIES);
+        true
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let dir = app_dir();
+        fs::create_dir_all(&dir)?;
+
+        let contents: String = self.entries
+            .iter()
+            .map(|entry| format!("{}\t{}\t{}\n", entry.score, entry.date, entry.name))
+            .collect();
+        fs::write(dir.join(HIGH_SCORES_FILE), contents)
+    }
+}
+
+impl Display for HighScores {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.entries.is_empty() {
+            return write!(f, "No high scores yet");
+        }
+
+        for (i, entry) in self.entries.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{:>2}. {:<16} {:>4}   {}", i + 1, entry.name, entry.score, entry.date)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,84 @@
+//! Where log messages go. Everything is written to stderr with the time, the
+//! level and the module it came from, e.g.
+//!
+//! ```text
+//! 14:02:11.532 INFO  [snakes::connect] Server listening on 0.0.0.0:9999
+//! ```
+//!
+//! How much gets written is up to the config file, which can also turn on
+//! more for just one module:
+//!
+//! ```text
+//! log.level = warn
+//! log.snakes::game = debug
+//! ```
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use super::config::Config;
+
+/// How much we log when the config doesn't say
+const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+
+struct Logger {
+    level: LevelFilter,
+    /// Modules that log more or less than everything else, longest first so
+    /// that the most specific one wins
+    modules: Vec<(String, LevelFilter)>,
+}
+
+/// Start logging as the config file says. Only the first call does anything.
+pub fn init(config: &Config) {
+    let level = config
+        .get("log.level")
+        .and_then(|level| level.parse().ok())
+        .unwrap_or(DEFAULT_LEVEL);
+    let mut modules: Vec<(String, LevelFilter)> = config
+        .with_prefix("log.")
+        .filter(|(module, _)| *module != "level")
+        .filter_map(|(module, level)| Some((module.to_string(), level.parse().ok()?)))
+        .collect();
+    modules.sort_by_key(|(module, _)| std::cmp::Reverse(module.len()));
+
+    let most = modules.iter().map(|(_, level)| *level).fold(level, Ord::max);
+    if log::set_boxed_logger(Box::new(Logger { level, modules })).is_ok() {
+        log::set_max_level(most);
+    }
+}
+
+impl Logger {
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .find(|(module, _)| target == module || target.starts_with(&format!("{}::", module)))
+            .map_or(self.level, |(_, level)| *level)
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let level = match record.level() {
+            Level::Error => "ERROR",
+            Level::Warn  => "WARN ",
+            Level::Info  => "INFO ",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        };
+        eprintln!(
+            "{} {} [{}] {}",
+            chrono::Local::now().format("%H:%M:%S%.3f"),
+            level,
+            record.target(),
+            record.args()
+        );
+    }
+
+    fn flush(&self) {}
+}
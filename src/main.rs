@@ -1,18 +1,31 @@
 extern crate byte_strings;
 
+mod config;
 mod connect;
+mod error;
 mod game;
+mod highscores;
+mod logging;
+mod netsim;
+mod settings;
+mod tournament;
 
-use connect::{server_main, client_main, make_ip};
+use connect::{server_main, client_main, local_main, single_main, resume_main, make_ip, Progress};
+use game::{Colour, Layout, Profile, SavedGame, Skin};
+use highscores::HighScores;
+use settings::{Settings, MAX_RECENT};
 
 use druid::{
-    widget::{Button, Flex, Label, Align, TextBox},
-    AppLauncher, LocalizedString,
+    widget::{Button, Either, Flex, Label, Align, Painter, TextBox},
+    AppDelegate, AppLauncher, Command, DelegateCtx, EventCtx, Handled, LocalizedString, RenderContext, Target,
     Widget, WidgetExt,
     WindowDesc, Data, Lens, Env
 };
 
 use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
 
 const VERTICAL_WIDGET_SPACING: f64 = 20.0;
 const HORIZONTAL_WIDGET_SPACING: f64 = 15.0;
@@ -20,14 +33,29 @@ const IP_ADD_WIDTH: f64 = 40.0;
 const WINDOW_TITLE: LocalizedString<InitState> = LocalizedString::new("Snake");
 
 fn main() {
+    logging::init(&config::Config::load());
+
+    // `snakes tournament ...` plays bots against each other without opening
+    // any windows
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("tournament") {
+        std::process::exit(tournament::main(&args[2..]));
+    }
+    // and `snakes proxy ...` makes the network between a client and a
+    // server worse, for testing
+    if args.get(1).map(String::as_str) == Some("proxy") {
+        std::process::exit(netsim::main(&args[2..]));
+    }
+
     // We initialize the initial state first
     let state = InitState::new();
 
     let app_window = WindowDesc::new(build_ui)
         .title(WINDOW_TITLE)
-        .window_size((400.0, 400.0));
+        .window_size((440.0, 860.0));
 
     AppLauncher::with_window(app_window)
+        .delegate(Delegate)
         .launch(state)
         .expect("Failed to launch application");
 }
@@ -52,6 +80,72 @@ fn build_ui() -> impl Widget<InitState> {
             (*data).ip1 = "127".into();
             (*data).ip4 = "1".into();
         });
+    let local_btn = Button::new("Local")
+        .on_click(|_ctx, data: &mut InitState, _env| {
+            // Both players share this keyboard, so there's nothing to connect to
+            data.mode = Mode::Local;
+        });
+    let single_btn = Button::new("Single player")
+        .on_click(|_ctx, data: &mut InitState, _env| {
+            data.mode = Mode::Single;
+        });
+
+    // A bot can fill the second seat of a local game or the empty seat on
+    // our server, or play for us as a client or in single-player
+    let bot_btn = Button::dynamic(|data: &InitState, _env: &Env| match data.bot {
+            Some(difficulty) => format!("Bot: {}", difficulty),
+            None             => "Bot: off".into(),
+        })
+        .on_click(|_ctx, data: &mut InitState, _env| {
+            data.bot = Difficulty::cycle(data.bot);
+        });
+
+    // Which keys the first and, in local games, the second player steer with
+    let keys1_btn = Button::dynamic(|data: &InitState, _env: &Env| format!("P1 keys: {}", data.keys1.name()))
+        .on_click(|_ctx, data: &mut InitState, _env| {
+            data.keys1 = data.keys1.cycle();
+        });
+    let keys2_btn = Button::dynamic(|data: &InitState, _env: &Env| format!("P2 keys: {}", data.keys2.name()))
+        .on_click(|_ctx, data: &mut InitState, _env| {
+            data.keys2 = data.keys2.cycle();
+        });
+    let keys_layout = Flex::row()
+        .with_child(keys1_btn)
+        .with_spacer(HORIZONTAL_WIDGET_SPACING)
+        .with_child(keys2_btn);
+
+    // The name the other player sees, which also goes into the high-score table
+    let name_label = Label::new("Name");
+    let name_textbox = TextBox::new()
+        .with_placeholder("Anonymous")
+        .fix_width(160.0)
+        .lens(InitState::player_name);
+    let name_layout = Flex::row()
+        .with_child(name_label)
+        .with_spacer(HORIZONTAL_WIDGET_SPACING)
+        .with_child(name_textbox);
+
+    // and the colour of our snake, next to a swatch of it, and its skin
+    let colour_btn = Button::dynamic(|data: &InitState, _env: &Env| format!("Colour: {}", data.colour.name()))
+        .on_click(|_ctx, data: &mut InitState, _env| {
+            data.colour = data.colour.cycle();
+        });
+    let colour_swatch = Painter::new(|ctx, data: &InitState, _env| {
+            let rect = ctx.size().to_rect();
+            let Colour { r, g, b } = data.colour;
+            ctx.fill(rect, &druid::Color::rgb8(r, g, b));
+        })
+        .fix_size(24.0, 24.0);
+    let skin_btn = Button::dynamic(|data: &InitState, _env: &Env| format!("Skin: {}", data.skin.name()))
+        .on_click(|_ctx, data: &mut InitState, _env| {
+            data.skin = data.skin.cycle();
+        });
+    let colour_layout = Flex::row()
+        .with_child(colour_btn)
+        .with_spacer(HORIZONTAL_WIDGET_SPACING)
+        .with_child(colour_swatch)
+        .with_spacer(HORIZONTAL_WIDGET_SPACING)
+        .with_child(skin_btn);
     
     /* Some widgets for asking for the IP Address */
     let ip_label = Label::new("IP Address");
@@ -91,20 +185,113 @@ fn build_ui() -> impl Widget<InitState> {
         .with_spacer(HORIZONTAL_WIDGET_SPACING)
         .with_child(port_textbox);
 
-    let enter_btn = Button::new("Connect")
+    // The servers we joined last time, to join again with one click
+    let mut recent_layout = Flex::row();
+    for i in 0..MAX_RECENT {
+        let recent_btn = Button::dynamic(move |data: &InitState, _env: &Env|
+                data.recent_servers.get(i).cloned().unwrap_or_default())
+            .on_click(move |_ctx, data: &mut InitState, _env| {
+                let server = data.recent_servers.get(i).and_then(|server| settings::split_server(server));
+                if let Some(([ip1, ip2, ip3, ip4], port)) = server {
+                    data.mode = Mode::Client;
+                    data.ip1 = ip1;
+                    data.ip2 = ip2;
+                    data.ip3 = ip3;
+                    data.ip4 = ip4;
+                    data.port_nbr = port;
+                }
+            });
+        recent_layout.add_child(Either::new(
+            move |data: &InitState, _env: &Env| i < data.recent_servers.len(),
+            recent_btn,
+            Label::new(""),
+        ));
+    }
+
+    // Both players have to give the same passphrase, if there is one
+    let passphrase_label = Label::new("Passphrase");
+    let passphrase_textbox = TextBox::new()
+        .with_placeholder("none")
+        .fix_width(160.0)
+        .lens(InitState::passphrase);
+    let passphrase_layout = Flex::row()
+        .with_child(passphrase_label)
+        .with_spacer(HORIZONTAL_WIDGET_SPACING)
+        .with_child(passphrase_textbox);
+
+    let enter_btn = Button::dynamic(|data: &InitState, _env: &Env| match data.mode {
+            Mode::Local | Mode::Single => "Start".into(),
+            _                          => "Connect".into(),
+        })
+        .on_click(|ctx, data: &mut InitState, _env| start(ctx, data, Launch::New));
+    // A local game saved from its pause menu can be picked up again
+    let resume_btn = Button::dynamic(|data: &InitState, _env: &Env|
+            format!("Resume {}", data.saved_game))
+        .on_click(|ctx, data: &mut InitState, _env| start(ctx, data, Launch::Resume));
+    let resume_or_nothing = Either::new(
+        |data: &InitState, _env: &Env| !data.saved_game.is_empty(),
+        resume_btn,
+        Label::new(""),
+    );
+
+    // While we wait for the other player, we can give up on them, and when
+    // something went wrong, we can try again
+    let cancel_btn = Button::new("Cancel").on_click(|_ctx, data: &mut InitState, _env| {
+        data.cancelled.store(true, Ordering::Relaxed);
+        // Anything the cancelled attempt still has to say is old news
+        data.attempt += 1;
+        data.connection_status = ConnectionStatus::NoAction;
+    });
+    let retry_btn = Button::new("Retry")
+        .on_click(|ctx, data: &mut InitState, _env| start(ctx, data, data.last_launch));
+    // and once a match is over, we can play the same again
+    let rematch_btn = Button::new("Rematch")
+        .on_click(|ctx, data: &mut InitState, _env| start(ctx, data, Launch::New));
+    // Nobody joined our server yet, so the bot can take their seat
+    let fill_seat_btn = Button::dynamic(|data: &InitState, _env: &Env| match data.bot {
+            Some(difficulty) => format!("Play the {} bot instead", difficulty),
+            None => String::new(),
+        })
         .on_click(|_ctx, data: &mut InitState, _env| {
-            // Form the IP Address
-            let ip = make_ip((*data).ip1.clone(), (*data).ip2.clone(), (*data).ip3.clone(), (*data).ip4.clone());
-            if (*data).mode == Mode::Server {
-                server_main(ip.clone(), (*data).port_nbr.clone(), data);
-            } else {
-                client_main(ip.clone(), (*data).port_nbr.clone());
-            }
-            
-            (*data).connection_status = ConnectionStatus::Connecting;
+            data.fill_seat.store(true, Ordering::Relaxed);
         });
+    let fill_seat_or_nothing = Either::new(
+        |data: &InitState, _env: &Env| {
+            data.bot.is_some() && matches!(data.connection_status, ConnectionStatus::Listening { joined: 0, .. })
+        },
+        fill_seat_btn,
+        Label::new(""),
+    );
+    let cancel_or_retry = Either::new(
+        |data: &InitState, _env: &Env| data.connection_status.is_waiting(),
+        cancel_btn,
+        Either::new(
+            |data: &InitState, _env: &Env| matches!(data.connection_status, ConnectionStatus::Failed(_)),
+            retry_btn,
+            Either::new(
+                |data: &InitState, _env: &Env| data.connection_status == ConnectionStatus::MatchOver,
+                rematch_btn,
+                Label::new(""),
+            ),
+        ),
+    );
+
     let status_label = Label::new(|data: &InitState, _env: &Env| 
         format!("{}", data.connection_status));
+    // Whatever went wrong with the last game, until the next one goes well
+    let error_label = Label::new(|data: &InitState, _env: &Env| match &data.connection_status {
+            ConnectionStatus::Failed(reason) => reason.clone(),
+            _ => String::new(),
+        })
+        .with_text_color(druid::Color::rgb8(0xE0, 0x40, 0x40));
+    // In single-player, we show the high-score table instead
+    let high_scores_label = Label::new(|data: &InitState, _env: &Env|
+        data.high_scores.clone());
+    let status_or_high_scores = Either::new(
+        |data: &InitState, _env: &Env| data.mode == Mode::Single,
+        high_scores_label,
+        status_label,
+    );
 
     let layout = Flex::column()
         .with_child(mode_label)
@@ -113,40 +300,232 @@ fn build_ui() -> impl Widget<InitState> {
         .with_spacer(VERTICAL_WIDGET_SPACING)
         .with_child(client_btn)
         .with_spacer(VERTICAL_WIDGET_SPACING)
+        .with_child(local_btn)
+        .with_spacer(VERTICAL_WIDGET_SPACING)
+        .with_child(single_btn)
+        .with_spacer(VERTICAL_WIDGET_SPACING)
+        .with_child(bot_btn)
+        .with_spacer(VERTICAL_WIDGET_SPACING)
+        .with_child(keys_layout)
+        .with_spacer(VERTICAL_WIDGET_SPACING)
+        .with_child(name_layout)
+        .with_spacer(VERTICAL_WIDGET_SPACING)
+        .with_child(colour_layout)
+        .with_spacer(VERTICAL_WIDGET_SPACING)
         .with_child(ip_layout)
         .with_spacer(VERTICAL_WIDGET_SPACING)
         .with_child(port_layout)
         .with_spacer(VERTICAL_WIDGET_SPACING)
+        .with_child(recent_layout)
+        .with_spacer(VERTICAL_WIDGET_SPACING)
+        .with_child(passphrase_layout)
+        .with_spacer(VERTICAL_WIDGET_SPACING)
         .with_child(enter_btn)
         .with_spacer(VERTICAL_WIDGET_SPACING)
-        .with_child(status_label);
+        .with_child(resume_or_nothing)
+        .with_spacer(VERTICAL_WIDGET_SPACING)
+        .with_child(cancel_or_retry)
+        .with_spacer(VERTICAL_WIDGET_SPACING)
+        .with_child(fill_seat_or_nothing)
+        .with_spacer(VERTICAL_WIDGET_SPACING)
+        .with_child(error_label)
+        .with_spacer(VERTICAL_WIDGET_SPACING)
+        .with_child(status_or_high_scores);
 
     Align::centered(layout)
 }
 
+/// Start a game the way the launcher is set up, on a thread of its own, so
+/// that the launcher can show how it's going in the meantime
+fn start(ctx: &mut EventCtx, data: &mut InitState, launch: Launch) {
+    if data.connection_status.is_busy() {
+        return;
+    }
+    data.attempt += 1;
+    data.cancelled = Arc::new(AtomicBool::new(false));
+    data.fill_seat = Arc::new(AtomicBool::new(false));
+    data.last_launch = launch;
+    // The game reads the key layouts from the config, so they have to be
+    // saved before it starts
+    data.save_settings();
+    let progress = Progress::new(
+        ctx.get_external_handle(),
+        data.attempt,
+        data.cancelled.clone(),
+        data.fill_seat.clone(),
+    );
+
+    // Form the IP Address
+    let ip = make_ip(data.ip1.clone(), data.ip2.clone(), data.ip3.clone(), data.ip4.clone());
+    let port = data.port_nbr.clone();
+    let profile = Profile::new(data.player_name.clone(), data.colour, data.skin);
+    let bot = data.bot;
+    let passphrase = data.passphrase.clone();
+    let mode = data.mode;
+
+    // Say what we're waiting for before we start, so that a second click
+    // finds us busy. Local games have nobody to wait for.
+    let address = [ip.clone(), port.clone()].join(":");
+    data.connection_status = match (launch, mode) {
+        (Launch::New, Mode::Server) => ConnectionStatus::Listening {
+            address,
+            joined: 0,
+            needed: connect::PLAYERS_NEEDED,
+        },
+        (Launch::New, Mode::Client) => ConnectionStatus::Connecting(address),
+        _ => ConnectionStatus::InGame,
+    };
+
+    thread::spawn(move || {
+        let result = match (launch, mode) {
+            (Launch::Resume, _)  => resume_main(profile, bot),
+            (_, Mode::Server) => server_main(ip, port, profile, bot, passphrase, &progress),
+            (_, Mode::Client) => client_main(ip, port, profile, bot, passphrase, &progress),
+            (_, Mode::Local)  => local_main(profile, bot),
+            (_, Mode::Single) => single_main(profile, bot),
+        };
+        progress.finish(result);
+    });
+}
+
+/// Hears from the threads that start games
+struct Delegate;
+
+impl AppDelegate<InitState> for Delegate {
+    fn command(
+        &mut self,
+        _ctx: &mut DelegateCtx,
+        _target: Target,
+        cmd: &Command,
+        data: &mut InitState,
+        _env: &Env,
+    ) -> Handled {
+        let (attempt, status) = match cmd.get(connect::STATUS) {
+            Some(news) => news,
+            None => return Handled::No,
+        };
+        if *attempt == data.attempt {
+            // A server that let us in goes to the top of the recent ones
+            if *status == ConnectionStatus::InGame && data.mode == Mode::Client {
+                let ip = make_ip(data.ip1.clone(), data.ip2.clone(), data.ip3.clone(), data.ip4.clone());
+                let server = format!("{}:{}", ip, data.port_nbr);
+                data.recent_servers = Arc::new(settings::remember_server(&data.recent_servers, &server));
+                data.save_settings();
+            }
+            data.connection_status = status.clone();
+        }
+        // A game might have ended, which might have been saved or made it
+        // into the table
+        if !data.connection_status.is_busy() {
+            data.high_scores = HighScores::load().to_string();
+            data.saved_game = InitState::describe_saved_game();
+        }
+        Handled::Yes
+    }
+}
+
 #[derive(Clone, Copy, PartialEq, Data)]
 pub enum Mode {
     Client,
     Server,
+    /// Both players share one keyboard and no connection is needed
+    Local,
+    /// A single snake on its own, trying to get a high score
+    Single,
 }
 
 impl Mode {
+    /// What the mode is called in the config file, saved games and logs
+    pub fn name(self) -> &'static str {
+        match self {
+            Mode::Server => "server",
+            Mode::Client => "client",
+            Mode::Local  => "local",
+            Mode::Single => "single",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [Mode::Server, Mode::Client, Mode::Local, Mode::Single]
+            .iter()
+            .copied()
+            .find(|mode| mode.name() == name)
+    }
+
     pub fn not(&self) -> Self {
         match self {
             Mode::Client => Mode::Server,
             Mode::Server => Mode::Client,
+            Mode::Local  => Mode::Local,
+            Mode::Single => Mode::Single,
         }
     }
 }
 
+/// How good a bot is, from heading straight for the food to planning
+/// a safe way there.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Data)]
+pub enum Difficulty {
+    Easy,
+    Medium,
+    Hard,
+}
+
+impl Difficulty {
+    /// The bot setting that comes after `bot` in the launcher, where `None`
+    /// means nobody is played by a bot.
+    pub fn cycle(bot: Option<Difficulty>) -> Option<Difficulty> {
+        match bot {
+            None                     => Some(Difficulty::Easy),
+            Some(Difficulty::Easy)   => Some(Difficulty::Medium),
+            Some(Difficulty::Medium) => Some(Difficulty::Hard),
+            Some(Difficulty::Hard)   => None,
+        }
+    }
+}
+
+/// How far the launcher has got with starting a game
 #[derive(Clone, PartialEq, Data)]
 pub enum ConnectionStatus {
     NoAction,
-    Connecting,
-    Connected,
+    /// The server is waiting on `address` until it has the players it needs
+    Listening { address: String, joined: u32, needed: u32 },
+    /// The client is trying to reach the server at this address
+    Connecting(String),
+    /// We reached the other side, and are making sure we can talk to each other
+    Handshake,
+    InGame,
+    /// The last match ended, and the launcher is ready for another one
+    MatchOver,
+    /// The last attempt went wrong, for this reason
+    Failed(String),
 }
 
-#[derive(Clone, PartialEq, Data, Lens)]
+impl ConnectionStatus {
+    /// Whether we're still waiting for a game to start
+    fn is_waiting(&self) -> bool {
+        matches!(
+            self,
+            ConnectionStatus::Listening { .. } | ConnectionStatus::Connecting(_) | ConnectionStatus::Handshake
+        )
+    }
+
+    /// Whether a game is starting or being played, so we can't start another
+    fn is_busy(&self) -> bool {
+        self.is_waiting() || *self == ConnectionStatus::InGame
+    }
+}
+
+/// What the launcher last started, so that it can be tried again
+#[derive(Clone, Copy, PartialEq, Data)]
+pub enum Launch {
+    /// A new game of whatever the launcher is set to
+    New,
+    /// The game saved from the pause menu
+    Resume,
+}
+
+#[derive(Clone, Data, Lens)]
 pub struct InitState {
     mode: Mode,
     connection_status: ConnectionStatus,
@@ -155,14 +534,56 @@ pub struct InitState {
     ip3: String,
     ip4: String,
     port_nbr: String,
+    player_name: String,
+    /// The colour of our snake
+    colour: Colour,
+    /// and how it's drawn
+    skin: Skin,
+    /// The high-score table, ready to be shown
+    high_scores: String,
+    /// How good the bot is, if one is playing
+    bot: Option<Difficulty>,
+    /// What both players have to know to play a networked match, if anything
+    passphrase: String,
+    /// What the game saved from the pause menu is, or empty if there's none
+    saved_game: String,
+    /// Which attempt at starting a game is the current one, so we can
+    /// ignore the ones that were cancelled
+    attempt: u32,
+    /// Set when the current attempt is cancelled
+    cancelled: Arc<AtomicBool>,
+    /// Set when the server should stop waiting and play the bot instead
+    fill_seat: Arc<AtomicBool>,
+    last_launch: Launch,
+    /// The key layouts of the first and second player
+    keys1: Layout,
+    keys2: Layout,
+    /// The servers we joined last, newest first
+    recent_servers: Arc<Vec<String>>,
 }
 
 impl Display for ConnectionStatus {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             ConnectionStatus::NoAction => write!(f, ""),
-            ConnectionStatus::Connected => write!(f, "connected"),
-            ConnectionStatus::Connecting => write!(f, "waiting..."),
+            ConnectionStatus::Listening { address, joined, needed } => {
+                write!(f, "listening on {}, waiting for players ({}/{})", address, joined, needed)
+            }
+            ConnectionStatus::Connecting(address) => write!(f, "connecting to {}...", address),
+            ConnectionStatus::Handshake => write!(f, "shaking hands..."),
+            ConnectionStatus::InGame => write!(f, "in game"),
+            ConnectionStatus::MatchOver => write!(f, "match over"),
+            ConnectionStatus::Failed(_) => write!(f, "failed"),
+        }
+    }
+}
+
+impl Display for Difficulty {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Difficulty::Easy   => write!(f, "easy"),
+            Difficulty::Medium => write!(f, "medium"),
+            Difficulty::Hard   => write!(f, "hard"),
         }
     }
 }
@@ -172,20 +593,61 @@ impl Display for Mode {
         match self {
             Mode::Client => write!(f, "Client"),
             Mode::Server => write!(f, "Server"),
+            Mode::Local  => write!(f, "playing locally"),
+            Mode::Single => write!(f, "playing alone"),
         }
     }
 }
 
 impl InitState {
+    /// The launcher the way it was left last time
     fn new() -> Self {
+        let Settings { mode, address, port, player_name, colour, skin, keys, recent } = Settings::load();
+        let [ip1, ip2, ip3, ip4] = address;
         InitState {
-            mode: Mode::Server,
+            mode,
             connection_status: ConnectionStatus::NoAction,
-            ip1: "0".into(),
-            ip2: "0".into(),
-            ip3: "0".into(),
-            ip4: "0".into(),
-            port_nbr: "9999".into(),
+            ip1,
+            ip2,
+            ip3,
+            ip4,
+            port_nbr: port,
+            player_name,
+            colour,
+            skin,
+            high_scores: HighScores::load().to_string(),
+            bot: None,
+            passphrase: String::new(),
+            saved_game: Self::describe_saved_game(),
+            attempt: 0,
+            cancelled: Arc::new(AtomicBool::new(false)),
+            fill_seat: Arc::new(AtomicBool::new(false)),
+            last_launch: Launch::New,
+            keys1: keys[0],
+            keys2: keys[1],
+            recent_servers: Arc::new(recent),
         }
     }
+
+    /// Remember how the launcher is set up for next time
+    fn save_settings(&self) {
+        let settings = Settings {
+            mode: self.mode,
+            address: [self.ip1.clone(), self.ip2.clone(), self.ip3.clone(), self.ip4.clone()],
+            port: self.port_nbr.clone(),
+            player_name: self.player_name.clone(),
+            colour: self.colour,
+            skin: self.skin,
+            keys: [self.keys1, self.keys2],
+            recent: self.recent_servers.to_vec(),
+        };
+        if let Err(e) = settings.save() {
+            log::warn!("Could not save the launcher settings: {}", e);
+        }
+    }
+
+    /// What the launcher says about the saved game, if there is one
+    fn describe_saved_game() -> String {
+        SavedGame::load().map(|saved| saved.describe()).unwrap_or_default()
+    }
 }
\ No newline at end of file
@@ -0,0 +1,378 @@
+//! A stand-in for a bad network, for testing the netcode without two
+//! machines on opposite sides of the world. Everything that goes through it
+//! comes out later, slower, or not at all, depending on the settings.
+//!
+//! It can sit in front of the game's own connection when the config says so,
+//! e.g.
+//!
+//! ```text
+//! netsim.delay_ms = 100
+//! netsim.jitter_ms = 20
+//! netsim.loss = 5
+//! ```
+//!
+//! or run on its own with `snakes proxy`, between a client and a server.
+//! The proxy only forwards TCP.
+//!
+//! Over UDP, a lost datagram is gone, and the link has to make up for it
+//! with the next one. TCP never loses anything we can see, so there a lost
+//! packet shows up the way it does on a real network: it's sent again after
+//! `RETRANSMIT_TIMEOUT`, and everything behind it waits.
+
+use log::{info, warn};
+use oorandom::Rand32;
+
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, TrySendError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::config::Config;
+
+const USAGE: &str = "\
+usage: snakes proxy [OPTIONS] LISTEN_ADDRESS SERVER_ADDRESS
+
+Listens on LISTEN_ADDRESS and forwards every connection to SERVER_ADDRESS,
+making the network look worse than it is on the way. The options default to
+the netsim settings in the config file.
+
+    --delay MS              delay everything by MS milliseconds each way
+    --jitter MS             and by up to MS more, at random
+    --bandwidth BYTES       send at most BYTES bytes per second each way
+    --loss PERCENT          lose PERCENT percent of the packets, which TCP
+                            then sends again 200 ms later
+    --disconnect-after S    hang up every connection after S seconds";
+
+/// How long TCP waits before it sends a lost packet again. The real thing
+/// depends on the connection, but it's never quick.
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(200);
+/// How much we read at once. Each read counts as one packet.
+const CHUNK_SIZE: usize = 1024;
+/// How many packets can be on their way in each direction at once. Over
+/// TCP, the sender has to wait for room, and over UDP, anything more is lost,
+/// the same as with a full buffer on a real network.
+const MAX_IN_FLIGHT: usize = 256;
+/// How often the UDP relay looks up to see if it's still needed
+const STOP_POLL: Duration = Duration::from_millis(100);
+
+/// How bad the network should be
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Settings {
+    pub delay: Duration,
+    /// Up to this much more delay, picked at random for every packet
+    pub jitter: Duration,
+    /// Bytes per second, or `None` for as fast as we can
+    pub bandwidth: Option<u64>,
+    /// The chance of losing a packet, from 0 to 1
+    pub loss: f32,
+    /// Hang up after this long, if at all
+    pub disconnect_after: Option<Duration>,
+}
+
+impl Settings {
+    /// The settings from the config file, or `None` if it doesn't ask for
+    /// a bad network at all.
+    pub fn from_config(config: &Config) -> Option<Self> {
+        let number = |key: &str| config.get(key).and_then(|value| value.trim().parse::<u64>().ok());
+
+        let settings = Settings {
+            delay: Duration::from_millis(number("netsim.delay_ms").unwrap_or(0)),
+            jitter: Duration::from_millis(number("netsim.jitter_ms").unwrap_or(0)),
+            bandwidth: number("netsim.bandwidth"),
+            loss: number("netsim.loss").unwrap_or(0).min(100) as f32 / 100.0,
+            disconnect_after: number("netsim.disconnect_after").map(Duration::from_secs),
+        };
+
+        if settings == Settings::default() {
+            None
+        } else {
+            Some(settings)
+        }
+    }
+}
+
+/// Put the simulated network between us and the other end of `stream`. The
+/// stream we get back works just like the original one, only worse.
+pub fn wrap(stream: TcpStream, settings: Settings) -> io::Result<TcpStream> {
+    // We connect to ourselves over loopback, and relay between the two
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let ours = TcpStream::connect(listener.local_addr()?)?;
+    let (theirs, _) = listener.accept()?;
+    ours.set_nodelay(true)?;
+    theirs.set_nodelay(true)?;
+
+    relay_both_ways(theirs, stream, settings)?;
+    Ok(ours)
+}
+
+/// Put the simulated network between us and the peer `socket` is connected
+/// to. We get back a socket that's connected to the relay instead, and the
+/// relay runs until the `Simulation` is dropped.
+pub fn wrap_udp(socket: UdpSocket, settings: Settings) -> io::Result<(UdpSocket, Simulation)> {
+    let ours = UdpSocket::bind("127.0.0.1:0")?;
+    let theirs = UdpSocket::bind("127.0.0.1:0")?;
+    ours.connect(theirs.local_addr()?)?;
+    theirs.connect(ours.local_addr()?)?;
+
+    let simulation = Simulation {
+        running: Arc::new(AtomicBool::new(true)),
+    };
+    let hang_up_at = settings.disconnect_after.map(|after| Instant::now() + after);
+    relay_datagrams(theirs.try_clone()?, socket.try_clone()?, settings, hang_up_at, &simulation.running)?;
+    relay_datagrams(socket, theirs, settings, hang_up_at, &simulation.running)?;
+    Ok((ours, simulation))
+}
+
+/// A simulated UDP network, which stops once this is dropped
+pub struct Simulation {
+    running: Arc<AtomicBool>,
+}
+
+impl Drop for Simulation {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Run `snakes proxy` with the arguments that follow it, returning the exit
+/// code.
+pub fn main(args: &[String]) -> i32 {
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{}", USAGE);
+        return 0;
+    }
+
+    let (settings, listen, server) = match parse_args(args) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            return 2;
+        }
+    };
+
+    let listener = match TcpListener::bind(&listen) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Could not listen on {}: {}", listen, e);
+            return 1;
+        }
+    };
+    info!("Forwarding {} to {} with {:?}", listen, server, settings);
+
+    for client in listener.incoming() {
+        let client = match client {
+            Ok(client) => client,
+            Err(e) => {
+                warn!("Could not accept a connection: {}", e);
+                continue;
+            }
+        };
+        let result = TcpStream::connect(&server)
+            .and_then(|server| relay_both_ways(client, server, settings));
+        match result {
+            Ok(()) => info!("Forwarding a new connection"),
+            Err(e) => warn!("Could not forward a connection: {}", e),
+        }
+    }
+
+    0
+}
+
+fn parse_args(args: &[String]) -> Result<(Settings, String, String), String> {
+    let mut settings = Settings::from_config(&Config::load()).unwrap_or_default();
+    let mut addresses = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut number = |name: &str| -> Result<u64, String> {
+            args.next()
+                .ok_or(format!("{} needs a value", name))?
+                .parse()
+                .map_err(|_| format!("{} needs a number", name))
+        };
+
+        match arg.as_str() {
+            "--delay"  => settings.delay = Duration::from_millis(number("--delay")?),
+            "--jitter" => settings.jitter = Duration::from_millis(number("--jitter")?),
+            "--bandwidth" => settings.bandwidth = Some(number("--bandwidth")?),
+            "--loss" => settings.loss = number("--loss")?.min(100) as f32 / 100.0,
+            "--disconnect-after" => {
+                settings.disconnect_after = Some(Duration::from_secs(number("--disconnect-after")?));
+            }
+            address => addresses.push(address.to_string()),
+        }
+    }
+
+    match <[String; 2]>::try_from(addresses) {
+        Ok([listen, server]) => Ok((settings, listen, server)),
+        Err(_) => Err("the proxy needs an address to listen on and a server to forward to".to_string()),
+    }
+}
+
+/// Relay between `a` and `b` in both directions, each on their own threads.
+fn relay_both_ways(a: TcpStream, b: TcpStream, settings: Settings) -> io::Result<()> {
+    let hang_up_at = settings.disconnect_after.map(|after| Instant::now() + after);
+    relay(a.try_clone()?, b.try_clone()?, settings, hang_up_at)?;
+    relay(b, a, settings, hang_up_at)
+}
+
+/// Forward everything `from` sends to `to`. One thread reads and decides
+/// when each packet should arrive, and another one delivers it then.
+fn relay(mut from: TcpStream, mut to: TcpStream, settings: Settings, hang_up_at: Option<Instant>) -> io::Result<()> {
+    let (sender, packets) = mpsc::sync_channel::<(Instant, Vec<u8>)>(MAX_IN_FLIGHT);
+    let mut schedule = Schedule::new(settings, Loss::Retransmit)?;
+
+    thread::spawn(move || {
+        let mut buffer = [0; CHUNK_SIZE];
+
+        loop {
+            let read = match from.read(&mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(read) => read,
+            };
+
+            // A lost packet is only late, see `Loss`
+            if let Some(arrival) = schedule.arrival(read) {
+                if sender.send((arrival, buffer[..read].to_vec())).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+
+    thread::spawn(move || {
+        loop {
+            // The simulated disconnect comes on time even if nobody says
+            // anything
+            let next = match hang_up_at {
+                Some(hang_up_at) => packets.recv_timeout(hang_up_at.saturating_duration_since(Instant::now())),
+                None => packets.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            let (arrival, packet) = match next {
+                Ok(next) => next,
+                Err(RecvTimeoutError::Timeout) => {
+                    let _ = to.shutdown(Shutdown::Both);
+                    return;
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            if let Some(hang_up_at) = hang_up_at {
+                if arrival >= hang_up_at {
+                    thread::sleep(hang_up_at.saturating_duration_since(Instant::now()));
+                    let _ = to.shutdown(Shutdown::Both);
+                    return;
+                }
+            }
+
+            thread::sleep(arrival.saturating_duration_since(Instant::now()));
+            if to.write_all(&packet).is_err() {
+                return;
+            }
+        }
+        // The sender hung up, so we pass that on
+        let _ = to.shutdown(Shutdown::Write);
+    });
+    Ok(())
+}
+
+/// Forward every datagram `from` gets to `to`, unless it's lost on the way,
+/// for as long as `running` says so. There's no hanging up over UDP, so after
+/// the simulated disconnect, nothing gets through anymore.
+fn relay_datagrams(
+    from: UdpSocket,
+    to: UdpSocket,
+    settings: Settings,
+    hang_up_at: Option<Instant>,
+    running: &Arc<AtomicBool>,
+) -> io::Result<()> {
+    let (sender, packets) = mpsc::sync_channel::<(Instant, Vec<u8>)>(MAX_IN_FLIGHT);
+    let mut schedule = Schedule::new(settings, Loss::Drop)?;
+    from.set_read_timeout(Some(STOP_POLL))?;
+    let running = Arc::clone(running);
+
+    thread::spawn(move || {
+        let mut buffer = [0; CHUNK_SIZE];
+
+        while running.load(Ordering::Relaxed) {
+            let read = match from.recv(&mut buffer) {
+                Ok(read) => read,
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => continue,
+                Err(_) => break,
+            };
+
+            let arrival = match schedule.arrival(read) {
+                Some(arrival) => arrival,
+                None => continue,
+            };
+            if let Err(TrySendError::Disconnected(_)) = sender.try_send((arrival, buffer[..read].to_vec())) {
+                break;
+            }
+        }
+    });
+
+    thread::spawn(move || {
+        for (arrival, packet) in packets {
+            if hang_up_at.is_some_and(|hang_up_at| arrival >= hang_up_at) {
+                return;
+            }
+            thread::sleep(arrival.saturating_duration_since(Instant::now()));
+            let _ = to.send(&packet);
+        }
+    });
+    Ok(())
+}
+
+/// What happens to a lost packet
+#[derive(Clone, Copy)]
+enum Loss {
+    /// TCP sends it again, so it only arrives late
+    Retransmit,
+    /// UDP doesn't, so it never arrives
+    Drop,
+}
+
+/// Decides when each packet going one way arrives
+struct Schedule {
+    settings: Settings,
+    loss: Loss,
+    rng: Rand32,
+    /// Nothing arrives before the packet that was sent ahead of it
+    last_arrival: Instant,
+}
+
+impl Schedule {
+    fn new(settings: Settings, loss: Loss) -> io::Result<Self> {
+        let mut seed = [0; 8];
+        getrandom::getrandom(&mut seed).map_err(|e| io::Error::other(e.to_string()))?;
+        Ok(Schedule {
+            settings,
+            loss,
+            rng: Rand32::new(u64::from_ne_bytes(seed)),
+            last_arrival: Instant::now(),
+        })
+    }
+
+    /// When a packet of `size` bytes that's sent now arrives, if at all
+    fn arrival(&mut self, size: usize) -> Option<Instant> {
+        let mut delay = self.settings.delay;
+        if self.settings.jitter > Duration::from_millis(0) {
+            delay += self.settings.jitter.mul_f32(self.rng.rand_float());
+        }
+        if self.rng.rand_float() < self.settings.loss {
+            match self.loss {
+                Loss::Retransmit => delay += RETRANSMIT_TIMEOUT,
+                Loss::Drop => return None,
+            }
+        }
+        let mut arrival = (Instant::now() + delay).max(self.last_arrival);
+        if let Some(bandwidth) = self.settings.bandwidth {
+            arrival += Duration::from_secs_f64(size as f64 / bandwidth.max(1) as f64);
+        }
+        self.last_arrival = arrival;
+        Some(arrival)
+    }
+}
@@ -0,0 +1,122 @@
+//! What the launcher remembers from one run to the next. It all goes into
+//! the config file next to everything else, e.g.
+//!
+//! ```text
+//! launcher.mode = client
+//! launcher.address = 192.168.1.20
+//! launcher.port = 9999
+//! launcher.name = Karl
+//! launcher.colour = purple
+//! launcher.skin = rounded
+//! launcher.recent = 192.168.1.20:9999, 10.0.0.7:4000
+//! keys1.layout = arrows
+//! keys2.layout = wasd
+//! ```
+//!
+//! where the recent servers are the ones we last joined, newest first.
+
+use std::io;
+
+use super::config::Config;
+use super::game::{Colour, Layout, Skin, PALETTE};
+use super::Mode;
+
+/// How many recent servers we remember
+pub const MAX_RECENT: usize = 3;
+
+pub struct Settings {
+    pub mode: Mode,
+    /// The four parts of the IP address
+    pub address: [String; 4],
+    pub port: String,
+    pub player_name: String,
+    /// The colour of our snake, by name or as `#rrggbb`
+    pub colour: Colour,
+    pub skin: Skin,
+    /// The key layouts of the first and second player
+    pub keys: [Layout; 2],
+    /// The servers we last joined, as `address:port`
+    pub recent: Vec<String>,
+}
+
+impl Settings {
+    /// What the launcher was set to last time, or the defaults for anything
+    /// it doesn't remember
+    pub fn load() -> Self {
+        let config = Config::load();
+
+        let mode = config.get("launcher.mode").and_then(Mode::from_name).unwrap_or(Mode::Server);
+        let address = config
+            .get("launcher.address")
+            .and_then(split_address)
+            .unwrap_or_else(|| default_address(mode));
+        let recent = config
+            .get("launcher.recent")
+            .map(|recent| {
+                recent
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|server| split_server(server).is_some())
+                    .take(MAX_RECENT)
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Settings {
+            mode,
+            address,
+            port: config.get("launcher.port").unwrap_or("9999").to_string(),
+            player_name: config.get("launcher.name").unwrap_or_default().to_string(),
+            colour: config.get("launcher.colour").and_then(Colour::from_name).unwrap_or(PALETTE[0].1),
+            skin: config.get("launcher.skin").and_then(Skin::from_name).unwrap_or_default(),
+            keys: [Layout::from_config(&config, 1), Layout::from_config(&config, 2)],
+            recent,
+        }
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let mut config = Config::load();
+        config.set("launcher.mode", self.mode.name());
+        config.set("launcher.address", &self.address.join("."));
+        config.set("launcher.port", &self.port);
+        config.set("launcher.name", &self.player_name);
+        config.set("launcher.colour", &self.colour.name());
+        config.set("launcher.skin", self.skin.name());
+        config.set("launcher.recent", &self.recent.join(", "));
+        config.set("keys1.layout", self.keys[0].name());
+        config.set("keys2.layout", self.keys[1].name());
+        config.save()
+    }
+}
+
+/// Put `server` at the top of the recent servers, without repeating it
+pub fn remember_server(recent: &[String], server: &str) -> Vec<String> {
+    std::iter::once(server.to_string())
+        .chain(recent.iter().filter(|other| *other != server).cloned())
+        .take(MAX_RECENT)
+        .collect()
+}
+
+/// The address the launcher starts out with for `mode`: every interface for
+/// a server, and this machine for everyone else
+pub fn default_address(mode: Mode) -> [String; 4] {
+    let first = if mode == Mode::Client { "127" } else { "0" };
+    let last = if mode == Mode::Client { "1" } else { "0" };
+    [first.into(), "0".into(), "0".into(), last.into()]
+}
+
+/// Split an `a.b.c.d` address into its four parts
+fn split_address(address: &str) -> Option<[String; 4]> {
+    let parts: Vec<&str> = address.split('.').collect();
+    match parts.as_slice() {
+        [a, b, c, d] => Some([a.to_string(), b.to_string(), c.to_string(), d.to_string()]),
+        _ => None,
+    }
+}
+
+/// Split an `a.b.c.d:port` server into the parts of its address and its port
+pub fn split_server(server: &str) -> Option<([String; 4], String)> {
+    let (address, port) = server.rsplit_once(':')?;
+    Some((split_address(address)?, port.to_string()))
+}
@@ -0,0 +1,394 @@
+use serde_json::{json, Value};
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use super::game::headless::{play_match, Contestant, MatchRecord};
+
+const USAGE: &str = "\
+usage: snakes tournament [--bracket] [--seed N] [--jobs N] [--out DIR] BOT BOT...
+
+Plays headless matches between bots and writes the standings, along with a
+replay of every match, to DIR (default: tournament).
+
+A BOT is one of
+    builtin:easy, builtin:medium, builtin:hard   one of our own bots
+    tcp:HOST:PORT                                a bot listening at an address
+    COMMAND                                      a program to start, quoted if
+                                                 it takes arguments
+
+By default every bot plays every other bot twice, once in each seat. With
+--bracket, bots play a single-elimination bracket in the order given instead.";
+
+/// How the contestants are paired up
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    /// Everybody plays everybody, once in each seat
+    RoundRobin,
+    /// Losers are out, until one bot is left
+    Bracket,
+}
+
+struct Options {
+    format: Format,
+    seed: u64,
+    jobs: usize,
+    out: PathBuf,
+    /// The contestants, along with how they were given on the command line
+    contestants: Vec<(String, Contestant)>,
+}
+
+/// A match that is about to be played
+#[derive(Clone, Copy, Debug)]
+struct Pairing {
+    id: usize,
+    /// The contestants in the first and second seat
+    seats: [usize; 2],
+    seed: u64,
+}
+
+/// A match that has been played, or that failed to start
+struct Played {
+    pairing: Pairing,
+    record: Result<MatchRecord, String>,
+}
+
+/// How a contestant did over the whole tournament
+#[derive(Clone, Debug, Default)]
+struct Standing {
+    played: u32,
+    wins: u32,
+    draws: u32,
+    losses: u32,
+    /// How much food the contestant ate over all its matches
+    food: u32,
+}
+
+impl Standing {
+    /// Three points for a win and one for a draw
+    fn points(&self) -> u32 {
+        self.wins * 3 + self.draws
+    }
+}
+
+/// Run `snakes tournament` with the arguments that follow it, returning the
+/// exit code.
+pub fn main(args: &[String]) -> i32 {
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{}", USAGE);
+        return 0;
+    }
+
+    let options = match parse_args(args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            return 2;
+        }
+    };
+
+    let played = match options.format {
+        Format::RoundRobin => round_robin(&options),
+        Format::Bracket    => bracket(&options),
+    };
+
+    match write_results(&options, &played) {
+        Ok(()) => {
+            println!("Results written to {}", options.out.display());
+            0
+        }
+        Err(e) => {
+            eprintln!("Could not write the results: {}", e);
+            1
+        }
+    }
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        format: Format::RoundRobin,
+        seed: 1,
+        jobs: thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+        out: PathBuf::from("tournament"),
+        contestants: Vec::new(),
+    };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next().cloned().ok_or(format!("{} needs a value", name))
+        };
+
+        match arg.as_str() {
+            "--bracket" => options.format = Format::Bracket,
+            "--seed" => {
+                options.seed = value("--seed")?.parse().map_err(|_| "--seed needs a number")?;
+            }
+            "--jobs" => {
+                options.jobs = value("--jobs")?.parse().map_err(|_| "--jobs needs a number")?;
+            }
+            "--out" => options.out = PathBuf::from(value("--out")?),
+            spec => options.contestants.push((spec.to_string(), Contestant::parse(spec)?)),
+        }
+    }
+
+    if options.contestants.len() < 2 {
+        return Err("a tournament needs at least two bots".to_string());
+    }
+    options.jobs = options.jobs.max(1);
+    Ok(options)
+}
+
+/// Play the matches on `jobs` threads at once, returning them in the order
+/// they were given.
+fn play_all(options: &Options, pairings: Vec<Pairing>) -> Vec<Played> {
+    let queue = Arc::new(Mutex::new(pairings.into_iter().collect::<VecDeque<_>>()));
+    let contestants: Arc<Vec<Contestant>> =
+        Arc::new(options.contestants.iter().map(|(_, c)| c.clone()).collect());
+    let (sender, receiver) = mpsc::channel();
+
+    for _ in 0..options.jobs {
+        let queue = Arc::clone(&queue);
+        let contestants = Arc::clone(&contestants);
+        let sender = sender.clone();
+
+        thread::spawn(move || loop {
+            let pairing = match queue.lock().unwrap().pop_front() {
+                Some(pairing) => pairing,
+                None          => break,
+            };
+            let seats = [&contestants[pairing.seats[0]], &contestants[pairing.seats[1]]];
+            let record = play_match(seats, pairing.seed).map_err(|e| e.to_string());
+
+            if sender.send(Played { pairing, record }).is_err() {
+                break;
+            }
+        });
+    }
+    drop(sender);
+
+    let mut played: Vec<Played> = receiver.iter().collect();
+    played.sort_by_key(|p| p.pairing.id);
+
+    for p in played.iter() {
+        let names = [
+            &options.contestants[p.pairing.seats[0]].0,
+            &options.contestants[p.pairing.seats[1]].0,
+        ];
+        match &p.record {
+            Ok(record) => match record.winner {
+                Some(seat) => println!("Match {}: {} beat {}", p.pairing.id, names[seat], names[1 - seat]),
+                None       => println!("Match {}: {} and {} drew", p.pairing.id, names[0], names[1]),
+            },
+            Err(e) => println!("Match {}: {} vs {} failed: {}", p.pairing.id, names[0], names[1], e),
+        }
+    }
+
+    played
+}
+
+fn round_robin(options: &Options) -> Vec<Played> {
+    let n = options.contestants.len();
+    let mut pairings = Vec::new();
+
+    for a in 0..n {
+        for b in 0..n {
+            if a != b {
+                let id = pairings.len() + 1;
+                pairings.push(Pairing {
+                    id,
+                    seats: [a, b],
+                    seed: options.seed.wrapping_add(id as u64),
+                });
+            }
+        }
+    }
+
+    play_all(options, pairings)
+}
+
+fn bracket(options: &Options) -> Vec<Played> {
+    let mut alive: Vec<usize> = (0..options.contestants.len()).collect();
+    let mut played = Vec::new();
+
+    while alive.len() > 1 {
+        let mut pairings = Vec::new();
+        let mut next_round = Vec::new();
+
+        for pair in alive.chunks(2) {
+            if let [a, b] = *pair {
+                let id = played.len() + pairings.len() + 1;
+                pairings.push(Pairing {
+                    id,
+                    seats: [a, b],
+                    seed: options.seed.wrapping_add(id as u64),
+                });
+            } else {
+                // An odd one out gets a bye into the next round
+                next_round.push(pair[0]);
+            }
+        }
+
+        let round = play_all(options, pairings);
+        for p in round.iter() {
+            // A draw goes to whoever ate more, and failing that, whoever was
+            // given first. A match that failed to start goes to the first seat.
+            let seat = match &p.record {
+                Ok(MatchRecord { winner: Some(seat), .. }) => *seat,
+                Ok(record) if record.scores[1] > record.scores[0] => 1,
+                _ => 0,
+            };
+            next_round.push(p.pairing.seats[seat]);
+        }
+        // Keep the order of the original seeding
+        next_round.sort_unstable();
+
+        played.extend(round);
+        alive = next_round;
+    }
+
+    played
+}
+
+fn standings(options: &Options, played: &[Played]) -> Vec<(usize, Standing)> {
+    let mut standings = vec![Standing::default(); options.contestants.len()];
+
+    for p in played.iter() {
+        let record = match &p.record {
+            Ok(record) => record,
+            Err(_)     => continue,
+        };
+
+        for seat in 0..2 {
+            let standing = &mut standings[p.pairing.seats[seat]];
+            standing.played += 1;
+            standing.food += record.scores[seat];
+            match record.winner {
+                Some(winner) if winner == seat => standing.wins += 1,
+                Some(_) => standing.losses += 1,
+                None    => standing.draws += 1,
+            }
+        }
+    }
+
+    let mut standings: Vec<(usize, Standing)> = standings.into_iter().enumerate().collect();
+    standings.sort_by(|(_, a), (_, b)| {
+        b.points()
+            .cmp(&a.points())
+            .then(b.wins.cmp(&a.wins))
+            .then(b.food.cmp(&a.food))
+    });
+    standings
+}
+
+/// Quote a CSV field if it needs it
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn write_results(options: &Options, played: &[Played]) -> io::Result<()> {
+    let out = &options.out;
+    let replays = out.join("replays");
+    fs::create_dir_all(&replays)?;
+    let name = |index: usize| options.contestants[index].0.as_str();
+
+    // The standings, best first
+    let standings = standings(options, played);
+    let mut csv = String::from("rank,bot,played,wins,draws,losses,points,food\n");
+    let mut json_standings = Vec::new();
+    for (rank, (index, s)) in standings.iter().enumerate() {
+        csv += &format!(
+            "{},{},{},{},{},{},{},{}\n",
+            rank + 1, csv_field(name(*index)), s.played, s.wins, s.draws, s.losses, s.points(), s.food,
+        );
+        json_standings.push(json!({
+            "rank": rank + 1,
+            "bot": name(*index),
+            "played": s.played,
+            "wins": s.wins,
+            "draws": s.draws,
+            "losses": s.losses,
+            "points": s.points(),
+            "food": s.food,
+        }));
+    }
+    fs::write(out.join("standings.csv"), csv)?;
+
+    // Every match, along with its replay
+    let mut csv = String::from("match,seed,bot1,bot2,winner,ticks,food1,food2,error\n");
+    let mut json_matches = Vec::new();
+    for p in played.iter() {
+        let bots = [name(p.pairing.seats[0]), name(p.pairing.seats[1])];
+
+        let summary = match &p.record {
+            Ok(record) => {
+                let winner = record.winner.map(|seat| bots[seat]);
+                write_replay(&replays, p.pairing.id, bots, record)?;
+                csv += &format!(
+                    "{},{},{},{},{},{},{},{},\n",
+                    p.pairing.id, record.seed, csv_field(bots[0]), csv_field(bots[1]),
+                    csv_field(winner.unwrap_or("")), record.ticks, record.scores[0], record.scores[1],
+                );
+                json!({
+                    "match": p.pairing.id,
+                    "seed": record.seed,
+                    "bots": bots,
+                    "winner": winner,
+                    "ticks": record.ticks,
+                    "food": record.scores,
+                })
+            }
+            Err(e) => {
+                csv += &format!(
+                    "{},{},{},{},,,,,{}\n",
+                    p.pairing.id, p.pairing.seed, csv_field(bots[0]), csv_field(bots[1]), csv_field(e),
+                );
+                json!({
+                    "match": p.pairing.id,
+                    "seed": p.pairing.seed,
+                    "bots": bots,
+                    "error": e,
+                })
+            }
+        };
+        json_matches.push(summary);
+    }
+    fs::write(out.join("matches.csv"), csv)?;
+
+    let format = match options.format {
+        Format::RoundRobin => "round-robin",
+        Format::Bracket    => "bracket",
+    };
+    let results = json!({
+        "format": format,
+        "seed": options.seed,
+        "standings": json_standings,
+        "matches": json_matches,
+    });
+    write_json(&out.join("standings.json"), &results)
+}
+
+fn write_replay(dir: &Path, id: usize, bots: [&str; 2], record: &MatchRecord) -> io::Result<()> {
+    let replay = json!({
+        "match": id,
+        "seed": record.seed,
+        "bots": bots,
+        "winner": record.winner.map(|seat| bots[seat]),
+        "food": record.scores,
+        "ticks": record.replay,
+    });
+    write_json(&dir.join(format!("match-{:03}.json", id)), &replay)
+}
+
+fn write_json(path: &Path, value: &Value) -> io::Result<()> {
+    fs::write(path, serde_json::to_string_pretty(value)?)
+}